@@ -1,14 +1,20 @@
-use utils::generate_strong_password;
-use password_manager_lib::encryption::*;
+use utils::{generate_strong_password, save_manifest_counter, seen_manifest_counter};
 use password_manager_lib::database::*;
-use password_manager_lib::encryption::*;
-use base64;
-use std::io::{self, Write};
+use password_manager_lib::crypto::{calibrate, calibrated_params, DEFAULT_UNLOCK_TIME};
+use password_manager_lib::encryption::{generate_key, Algorithm};
+use password_manager_lib::keystore::{self, FileKeyStore, KeyStore, SecretServiceKeyStore};
+use password_manager_lib::provider::{self, Provider, UnlockedVaults};
+use password_manager_lib::secret::{SecretKey, SecretString};
+use password_manager_lib::totp::{self, OtpAuth, OtpKind};
+use qrcode::QrCode;
+use qrcode::render::unicode::Dense1x2;
+use std::io;
+use std::time::Duration;
 use ratatui::layout::Direction;
 use ratatui::widgets::{List, ListItem, ListState};
 use ratatui::layout::Rect;
 use rusqlite::Connection;
-use ratatui::widgets::Wrap;
+use ratatui::widgets::{BorderType, Wrap};
 use ratatui::text::Text;
 use ratatui::text::Line;
 use crossterm::{
@@ -38,9 +44,9 @@ enum AppState {
     Register {
         step: usize,
         username: String,
-        password: String,
-        password2: String,
-        input_buffer: String,
+        password: SecretString,
+        password2: SecretString,
+        input_buffer: SecretString,
         cursor_pos: usize,
         error_message: Option<String>,
         error_time: Option<std::time::Instant>,
@@ -48,76 +54,192 @@ enum AppState {
     Login {
         step: usize,
         username: String,
-        password: String,
-        input_buffer: String,
+        password: SecretString,
+        input_buffer: SecretString,
         cursor_pos: usize,
         error_message: Option<String>,
         error_time: Option<std::time::Instant>,
+        key_file: Option<SecretKey>,
+        // Set while the library refuses further attempts, the error box counts down to it
+        retry_at: Option<std::time::Instant>,
+        // Unlocked by the password, handed over once the authentication code checks out
+        pending_session: Option<Session>,
     },
     CreateAccount {
         user_id: i64,
         step: usize,
         account: String,
         username: String,
-        password: String,
-        input_buffer: String,
+        password: SecretString,
+        input_buffer: SecretString,
         cursor_pos: usize,
+        error_message: Option<String>,
     },
     ShowAllVaults {
         user_id: i64,
-        entries: Vec<(String, String, String)>,
+        entries: Vec<VaultEntry>,
+        // Shown in place of the list when there are no entries
+        empty_message: &'static str,
         scroll: u16,
         selected: usize,
-        show_password: bool,
         show_headers: bool,
+        error_message: Option<String>,
     },
     ViewVaultDetail {
         user_id: i64,
-        account: String,
-        username: String,
-        password: String,
-        previous_entries: Vec<(String, String, String)>,
+        entry: VaultEntry,
+        previous_entries: Vec<VaultEntry>,
         previous_scroll: u16,
         previous_selected: usize,
         scroll: u16,
         previous_show_headers: bool,
 
-        email_emoji_pos: Option<(u16, u16)>,
-        pass_emoji_pos: Option<(u16, u16)>,
-
         copy_message: Option<(String, std::time::Instant)>,
         obscure_password: bool,
+        error_message: Option<String>,
     },
     EditVault {
         user_id: i64,
         step: usize,
-        account: String,
-        username: String,
-        password: String,
-        input_buffer: String,
-        old_account: String,
-        old_username: String,
+        // The entry as stored, until the last step writes the edited fields over it
+        entry: VaultEntry,
+        input_buffer: SecretString,
 
         temp_account: String,
         temp_username: String,
-        temp_password: String,
+        temp_password: SecretString,
 
-        previous_entries: Vec<(String, String, String)>,
+        previous_entries: Vec<VaultEntry>,
         previous_scroll: u16,
         previous_selected: usize,
         previous_show_headers: bool,
 
-        started_editing: bool,
-
         cursor_pos: usize,
+        error_message: Option<String>,
     },
     SearchVault {
         user_id: i64,
         input_buffer: String,
-    }
+    },
+    RotateKeys {
+        user_id: i64,
+        error_message: Option<String>,
+    },
+    ChangePassword {
+        user_id: i64,
+        step: usize,
+        old_password: SecretString,
+        password: SecretString,
+        password2: SecretString,
+        input_buffer: SecretString,
+        cursor_pos: usize,
+        error_message: Option<String>,
+        error_time: Option<std::time::Instant>,
+    },
+    RotationSettings {
+        user_id: i64,
+        input_buffer: String,
+        error_message: Option<String>,
+    },
+    TwoFactor {
+        user_id: i64,
+        // Secret being enrolled and its QR code, None when two-factor login is already on
+        enrollment: Option<(SecretKey, String)>,
+        input_buffer: String,
+        error_message: Option<String>,
+    },
+    RecoveryKey {
+        user_id: i64,
+        words: SecretString,
+    },
+    ForgotPassword {
+        step: usize,
+        username: String,
+        recovery_words: SecretString,
+        password: SecretString,
+        password2: SecretString,
+        input_buffer: SecretString,
+        cursor_pos: usize,
+        error_message: Option<String>,
+        error_time: Option<std::time::Instant>,
+    },
+    KeyFile {
+        user_id: i64,
+        step: usize,
+        password: SecretString,
+        input_buffer: SecretString,
+        cursor_pos: usize,
+        error_message: Option<String>,
+        error_time: Option<std::time::Instant>,
+    },
+    RecoveryCodes {
+        user_id: i64,
+        // Freshly generated codes, shown until the screen is left
+        codes: Vec<SecretString>,
+        remaining: i64,
+        error_message: Option<String>,
+    },
+    RememberDevice {
+        user_id: i64,
+        username: String,
+        remembered: bool,
+        error_message: Option<String>,
+    },
+    // The vault failed its integrity check right after login
+    Tampered {
+        user_id: i64,
+        message: String,
+        error_message: Option<String>,
+    },
+    // Some entries did not decrypt when the vault was opened
+    UnreadableEntries {
+        user_id: i64,
+        count: usize,
+    },
+    // Typing the username confirms, the master password then authorizes the deletion
+    DeleteAccount {
+        user_id: i64,
+        username: String,
+        step: usize,
+        input_buffer: SecretString,
+        cursor_pos: usize,
+        error_message: Option<String>,
+    },
 }
 // Setting up console environment
 fn main() -> Result<(), Box<dyn Error>> {
+    // `--unlock-time <ms>` is the unlock latency registering suggests for new accounts instead of the default
+    // `--cipher <aes-256-gcm|xchacha20-poly1305>` picks the cipher new accounts are sealed with
+    // `--secret-service` serves the vault of the logged in user to other applications over D-Bus
+    let mut unlock_time = None;
+    let mut cipher = Algorithm::preferred();
+    let mut secret_service = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--unlock-time" => {
+                let millis: u64 = args.next().ok_or("--unlock-time expects milliseconds")?.parse()?;
+                unlock_time = Some(Duration::from_millis(millis));
+            }
+            "--cipher" => {
+                cipher = args.next().ok_or("--cipher expects a cipher name")?.parse()?;
+            }
+            "--secret-service" => secret_service = true,
+            _ => return Err(format!("Unknown argument: {}", arg).into()),
+        }
+    }
+
+    // Calibrated up front, so the first login of an unknown name pays no more than any other
+    std::thread::spawn(calibrated_params);
+
+    let vaults = UnlockedVaults::default();
+    if secret_service {
+        // Claimed before the terminal is taken over, so a running keyring is reported plainly
+        let bus = provider::connect(None)?;
+        let provider = Provider::new(initialize_db("passwords.db")?, vaults.clone());
+        std::thread::spawn(move || provider.serve(&bus));
+    }
+
     enable_raw_mode()?;
     let mut stdout = io::stdout();
 
@@ -125,12 +247,17 @@ fn main() -> Result<(), Box<dyn Error>> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let key = [42u8; 32]; // šifrovací kľúč (Key)
     let conn = initialize_db("passwords.db")?;
+    // Remembered devices keep their keys in the desktop keyring, or in files next to the database without one.
+    // While serving the keyring ourselves the key would end up in the vault it unlocks.
+    let key_store: Box<dyn KeyStore> = match SecretServiceKeyStore::connect() {
+        Ok(store) if !secret_service => Box::new(store),
+        _ => Box::new(FileKeyStore::new("device_keys")),
+    };
 
     let mut state = AppState::Start;
 
-    let result = run_app(&mut terminal, &key, &conn, &mut state);
+    let result = run_app(&mut terminal, &conn, &mut state, unlock_time, cipher, key_store.as_ref(), &vaults);
 
     disable_raw_mode().ok();
     execute!(
@@ -147,56 +274,138 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-struct MenuState {
-    selected: usize,
+// Screen a successful login lands on
+fn logged_in_state(conn: &Connection, user_id: i64) -> Result<AppState, Box<dyn Error>> {
+    Ok(if totp_reenrollment_required(conn, user_id)? {
+        two_factor_state(conn, user_id, Some("A recovery code was used, set up your authenticator again".to_string()))?
+    } else if rotation_due(conn, user_id)? {
+        AppState::RotateKeys { user_id, error_message: None }
+    } else {
+        AppState::Menu { user_id }
+    })
 }
 
-impl MenuState {
-    fn new() -> Self {
-        Self { selected: 0 }
-    }
-
-    fn next(&mut self, max: usize) {
-        if self.selected < max - 1 {
-            self.selected += 1;
-        }
-    }
+// Two-factor settings, enrolling a new secret unless it is already on
+fn two_factor_state(conn: &Connection, user_id: i64, error_message: Option<String>) -> Result<AppState, Box<dyn Error>> {
+    let enrollment = if totp_enabled(conn, user_id)? {
+        None
+    } else {
+        let username: String = conn.query_row("SELECT username FROM users WHERE id = ?1", [user_id], |row| row.get(0))?;
+        let secret = totp::generate_secret();
+        let qr_code = QrCode::new(totp::provisioning_uri(&secret, &username))?
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build();
+        Some((secret, qr_code))
+    };
+
+    Ok(AppState::TwoFactor {
+        user_id,
+        enrollment,
+        input_buffer: String::new(),
+        error_message,
+    })
+}
 
-    fn previous(&mut self) {
-        if self.selected > 0 {
-            self.selected -= 1;
+// Alphabetical by site, then by login
+fn sort_entries(entries: &mut [VaultEntry]) {
+    entries.sort_by(|a, b| {
+        let site_cmp = a.account.to_lowercase().cmp(&b.account.to_lowercase());
+        if site_cmp == std::cmp::Ordering::Equal {
+            a.username.to_lowercase().cmp(&b.username.to_lowercase())
+        } else {
+            site_cmp
         }
-    }
+    });
 }
 
-const START_ITEMS: [&str; 3] = [
+// Highest manifest counter seen per user, next to the database
+const MANIFEST_COUNTERS_FILE: &str = "manifest_counters";
+
+const NO_VAULTS: &str = "No vaults created yet.";
+const NO_RESULTS: &str = "Sorry, no results :(";
+
+// Room reserved in input buffers so typing a secret never reallocates and leaves a copy behind
+const INPUT_CAPACITY: usize = 256;
+
+const START_ITEMS: [&str; 4] = [
     "Login",
     "Register",
+    "Forgot password",
     "End"
 ];
-const MENU_ITEMS: [&str; 4] = [
+const MENU_ITEMS: [&str; 11] = [
     "Create vault",
     "Search vault",
     "Show all vaults",
+    "Key rotation",
+    "Change master password",
+    "Two-factor authentication",
+    "Recovery codes",
+    "Key file",
+    "Remember this device",
+    "Delete my account",
     "Logout",
 ];
 
-fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8; 32], conn: &Connection, state: &mut AppState,) -> Result<(), Box<dyn std::error::Error>> {
+fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, conn: &Connection, state: &mut AppState, unlock_time: Option<Duration>, cipher: Algorithm, key_store: &dyn KeyStore, vaults: &UnlockedVaults) -> Result<(), Box<dyn std::error::Error>> {
     let mut list_state = ListState::default();
     list_state.select(Some(0));
+    let mut session: Option<Session> = None;
+    // Contents of the key file the session was unlocked with, needed again to change credentials
+    let mut key_file: Option<SecretKey> = None;
+    // User and key version of the session the Secret Service provider last got
+    let mut served: Option<(i64, i64)> = None;
+    // User whose vault passed the manifest check, with the highest counter recorded for it since
+    let mut verified: Option<(i64, i64)> = None;
     
     loop {
-        terminal.draw(|f| {
-            let size = f.size();
+        match &session {
+            Some(current) if verified.map(|(user_id, _)| user_id) != Some(current.user_id) => {
+                let seen = seen_manifest_counter(MANIFEST_COUNTERS_FILE, current.user_id);
+                match verify_manifest(conn, current, seen) {
+                    Ok(counter) => {
+                        save_manifest_counter(MANIFEST_COUNTERS_FILE, current.user_id, counter)?;
+                        verified = Some((current.user_id, counter));
+                        let unreadable = unreadable_entries(conn, current)?;
+                        if !unreadable.is_empty() {
+                            *state = AppState::UnreadableEntries { user_id: current.user_id, count: unreadable.len() };
+                        }
+                    }
+                    Err(err) => {
+                        *state = AppState::Tampered { user_id: current.user_id, message: err.to_string(), error_message: None };
+                        verified = Some((current.user_id, seen.unwrap_or(0)));
+                    }
+                }
+            }
+            Some(current) => {
+                // Every change the vault makes raises the counter, which this device then has seen
+                if let (Some(counter), Some((user_id, recorded))) = (manifest_counter(conn, current.user_id)?, verified) {
+                    if counter > recorded {
+                        save_manifest_counter(MANIFEST_COUNTERS_FILE, user_id, counter)?;
+                        verified = Some((user_id, counter));
+                    }
+                }
+            }
+            None => verified = None,
+        }
 
-            let block = Block::default()
-                .title("My Password Manager")
-                .borders(Borders::ALL)
-                .style(Style::default().fg(Color::Cyan));
+        let current = session.as_ref().map(|session| (session.user_id, session.key_version));
+        if current != served {
+            if let Some((user_id, _)) = served {
+                vaults.lock(user_id);
+            }
+            if let Some(session) = &session {
+                vaults.unlock(session.clone());
+            }
+            served = current;
+        }
 
+        terminal.draw(|f| {
             let items: Vec<ListItem> = match state {
                 AppState::Start => START_ITEMS.iter().map(|item| ListItem::new(*item)).collect(),
-                AppState::Menu {user_id}=> MENU_ITEMS.iter().map(|item| ListItem::new(*item)).collect(),
+                AppState::Menu { .. } => MENU_ITEMS.iter().map(|item| ListItem::new(*item)).collect(),
                 _ => vec![ListItem::new("Currently working in the right terminal.")],
             };
 
@@ -206,8 +415,6 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                 .constraints([Constraint::Percentage(40), Constraint::Percentage(60)].as_ref())
                 .split(f.size());
 
-            let visible_lines = chunks[1].height as usize;
-
             let list = List::new(items)
                 .block(
                     Block::default()
@@ -234,30 +441,17 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                     f.render_widget(paragraph, chunks[1]);
                 }
 
-                AppState::Register {step, username, password, password2, input_buffer, cursor_pos, error_message, error_time} => {
+                AppState::Register {step, input_buffer, cursor_pos, error_message, ..} => {
                     let label = match step {
                         0 => "Enter nickname:",
                         1 => "Enter password:",
                         2 => "Re-enter password",
+                        3 => "Unlock time in milliseconds (slower is harder to guess):",
                         _ => "Finito!",
                     };
 
                     let cursor_pos = std::cmp::min(*cursor_pos, input_buffer.len());
 
-                    let before = &input_buffer[..cursor_pos];
-                    let cursor_char = input_buffer
-                        .chars()
-                        .nth(cursor_pos)
-                        .unwrap_or(' ');
-
-                    let after = if cursor_pos < input_buffer.len() {
-                        &input_buffer[cursor_pos + cursor_char.len_utf8()..]
-                    } else {
-                    ""
-                    };
-
-                    let cursor_pos = std::cmp::min(cursor_pos, input_buffer.len());
-
                     let before = &input_buffer[..cursor_pos];
                     let cursor_char = input_buffer.chars().nth(cursor_pos).unwrap_or(' ');
                     let after = if cursor_pos < input_buffer.len() {
@@ -312,29 +506,17 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                     }
                 }
 
-                AppState::Login {step, username, password, input_buffer, cursor_pos, error_message, error_time} => {
+                AppState::Login {step, input_buffer, cursor_pos, error_message, retry_at, ..} => {
                     let label = match step {
                         0 => "Enter nickname:",
                         1 => "Enter password:",
+                        2 => "Enter key file path:",
+                        3 => "Enter authentication code or a recovery code:",
                         _ => "Finito!",
                     };
 
                     let cursor_pos = std::cmp::min(*cursor_pos, input_buffer.len());
 
-                    let before = &input_buffer[..cursor_pos];
-                    let cursor_char = input_buffer
-                        .chars()
-                        .nth(cursor_pos)
-                        .unwrap_or(' ');
-
-                    let after = if cursor_pos < input_buffer.len() {
-                        &input_buffer[cursor_pos + cursor_char.len_utf8()..]
-                    } else {
-                        ""
-                    };
-
-                    let cursor_pos = std::cmp::min(cursor_pos, input_buffer.len());
-
                     let before = &input_buffer[..cursor_pos];
                     let cursor_char = input_buffer.chars().nth(cursor_pos).unwrap_or(' ');
                     let after = if cursor_pos < input_buffer.len() {
@@ -371,7 +553,13 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                     let cursor_y = chunks[1].y + 2;
                     f.set_cursor(cursor_x, cursor_y);
 
-                    if let Some(msg) = error_message {
+                    let wait = retry_at.map(|at| at.saturating_duration_since(std::time::Instant::now()).as_secs_f32().ceil() as u64);
+                    let msg = match wait {
+                        Some(seconds) if seconds > 0 => Some(format!("Too many failed attempts, try again in {} s", seconds)),
+                        _ => error_message.clone(),
+                    };
+
+                    if let Some(msg) = msg {
                         let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
                             msg.as_str(),
                             Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
@@ -389,37 +577,24 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                     }
                 }
 
-                AppState::Menu {user_id} => {
+                AppState::Menu { .. } => {
                     let paragraph = Paragraph::new("Pick option in menu.")
                         .style(Style::default().fg(Color::Rgb(0, 255, 255)))
                         .block(Block::default().title("Action").borders(Borders::ALL));
                     f.render_widget(paragraph, chunks[1]);
                 }
 
-                AppState::CreateAccount {user_id, step, input_buffer, account, username, password, cursor_pos} => {
+                AppState::CreateAccount {step, input_buffer, cursor_pos, error_message, ..} => {
                     let label = match step {
                         0 => "Enter website name:",
                         1 => "Enter email/username:",
                         2 => "Enter password: (# - generate safe password)",
+                        3 => "Enter authenticator otpauth:// URI (optional):",
                         _ => "Finito!",
                     };
 
                     let cursor_pos = std::cmp::min(*cursor_pos, input_buffer.len());
 
-                    let before = &input_buffer[..cursor_pos];
-                    let cursor_char = input_buffer
-                        .chars()
-                        .nth(cursor_pos)
-                        .unwrap_or(' ');
-
-                    let after = if cursor_pos < input_buffer.len() {
-                        &input_buffer[cursor_pos + cursor_char.len_utf8()..]
-                    } else {
-                        ""
-                    };
-
-                    let cursor_pos = std::cmp::min(cursor_pos, input_buffer.len());
-
                     let before = &input_buffer[..cursor_pos];
                     let cursor_char = input_buffer.chars().nth(cursor_pos).unwrap_or(' ');
                     let after = if cursor_pos < input_buffer.len() {
@@ -455,20 +630,35 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                     let cursor_x = chunks[1].x + 1 + actual_pos as u16;
                     let cursor_y = chunks[1].y + 2;
                     f.set_cursor(cursor_x, cursor_y);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + 4,
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
                 }
 
-                AppState::ViewVaultDetail { account, username, password, scroll, copy_message, obscure_password, ..} => {
+                AppState::ViewVaultDetail { entry, scroll, copy_message, obscure_password, error_message, ..} => {
                     let display_password = if *obscure_password {
-                        "•".repeat(password.chars().count())
+                        SecretString::from("•".repeat(entry.password.chars().count()))
                     } else {
-                        password.clone()
+                        entry.password.clone()
                     };
 
-                    let labels = vec![
-                        ("Website", account.as_str()),
-                        ("Email/Username", username.as_str()),
-                        ("Password", display_password.as_str()),
-                    ];
+                    let labels = [("Website", entry.account.as_str()),
+                        ("Email/Username", entry.username.as_str()),
+                        ("Password", display_password.as_str())];
 
 
                     //let mut email_pos = None;
@@ -518,9 +708,41 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                         })
                         .collect();
 
-                    let visible_lines = chunks[1].height.saturating_sub(2) as usize;
-                    let max_scroll = content.len().saturating_sub(visible_lines);
-                    let actual_scroll = (*scroll as usize).min(max_scroll) as u16;
+                    let mut content = content;
+                    if let Some(otp) = &entry.otp {
+                        let now = totp::unix_time();
+                        let code = otp.code(now);
+                        let (first, second) = code.split_at(code.len() / 2);
+
+                        let copied_now = copy_message.as_ref()
+                            .map(|(msg, time)| time.elapsed().as_secs_f32() < 1.4 && msg.contains("Code"))
+                            .unwrap_or(false);
+
+                        let mut code_line = vec![Span::styled(format!("{} {}", first, second), Style::default().fg(Color::White).add_modifier(Modifier::BOLD))];
+                        // Countdown until the code changes, HOTP codes only change when used
+                        if let (Some(left), OtpKind::Totp { period }) = (otp.seconds_left(now), otp.kind) {
+                            let width = 20;
+                            let filled = (left * width / period) as usize;
+                            code_line.push(Span::raw("  "));
+                            code_line.push(Span::styled(
+                                "█".repeat(filled),
+                                Style::default().fg(if left <= 5 { Color::Rgb(255, 60, 60) } else { Color::Rgb(0, 225, 0) }),
+                            ));
+                            code_line.push(Span::styled("░".repeat(width as usize - filled), Style::default().fg(Color::DarkGray)));
+                            code_line.push(Span::styled(format!(" {}s", left), Style::default().fg(Color::White)));
+                        }
+
+                        content.push(Line::from(vec![
+                            Span::styled("Authenticator", Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD)),
+                            Span::raw(" "),
+                            Span::styled(
+                                if copied_now { "(Copied Successfully!)" } else { "📋 (Copy code - O)" },
+                                Style::default().fg(if copied_now { Color::Rgb(0, 225, 0) } else { Color::White }),
+                            ),
+                        ]));
+                        content.push(Line::from(code_line));
+                        content.push(Line::from(""));
+                    }
 
                     let paragraph = Paragraph::new(Text::from(content))
                         .block(Block::default().title("Vault details (Edit - E, Delete - D, Go back - Esc)").borders(Borders::ALL))
@@ -528,24 +750,39 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                         .wrap(Wrap { trim: false });
 
                     f.render_widget(paragraph.scroll((*scroll, 0)), chunks[1]);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + chunks[1].height.saturating_sub(3),
+                            width: chunks[1].width,
+                            height: 3.min(chunks[1].height),
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
                 }
 
 
-                AppState::ShowAllVaults {user_id, entries, scroll, selected, show_password, show_headers } => {
+                AppState::ShowAllVaults {entries, empty_message, scroll, selected, show_headers, error_message, .. } => {
                     let mut lines = vec![];
                     let mut last_letter: Option<char> = None;
                     let mut entry_line_indices = vec![];
 
-                    for (i, (acc, user, enc)) in entries.iter().enumerate() {
-                        if acc == "No vaults created yet." {
-                            let msg = Span::styled(
-                                "No vaults created yet.",
-                                Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD),
-                            );
-                            lines.push(Line::from(vec![msg]));
-                            continue;
-                        }
+                    if entries.is_empty() {
+                        lines.push(Line::from(Span::styled(
+                            *empty_message,
+                            Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD),
+                        )));
+                    }
 
+                    for (i, VaultEntry { account: acc, username: user, .. }) in entries.iter().enumerate() {
                         let first_letter = acc.chars().next().unwrap_or('?').to_ascii_uppercase();
 
                         if *show_headers && Some(first_letter) != last_letter {
@@ -558,7 +795,7 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                             last_letter = Some(first_letter);
                         }
 
-                        let mut line = if user.is_empty() {
+                        let line = if user.is_empty() {
                             acc.clone()
                         } else {
                             format!("{} | {}", acc, user)
@@ -574,9 +811,7 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                             last_letter = Some(first_letter);
                         }
 
-                        let styled_line = if acc == "Sorry, no results :(" {
-                            Span::styled(line, Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))
-                        } else if i == *selected {
+                        let styled_line = if i == *selected {
                             Span::styled(line, Style::default().fg(Color::Rgb(255, 165, 0)).add_modifier(Modifier::BOLD))
                         } else {
                             Span::styled(line, Style::default().fg(Color::White))
@@ -613,9 +848,25 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
 
                     f.render_widget(paragraph, chunks[1]);
 
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + chunks[1].height.saturating_sub(3),
+                            width: chunks[1].width,
+                            height: 3.min(chunks[1].height),
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
                 }
                 
-                AppState::SearchVault {user_id, input_buffer } => {
+                AppState::SearchVault { input_buffer, .. } => {
                     let lines = vec![
                         Line::from(Span::styled("Enter website name to filter:", Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
                         Line::from(Span::styled(input_buffer.as_str(), Style::default().fg(Color::White))),
@@ -627,162 +878,1326 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                     f.render_widget(paragraph, chunks[1]);
                 }
 
-                AppState::EditVault { step, input_buffer, account, username, password, cursor_pos, ..} => {
+                AppState::RotateKeys { error_message, .. } => {
+                    let lines = vec![
+                        Line::from(Span::styled("Your vault keys are due for rotation.", Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(Span::styled("Rotate now? Every entry will be re-encrypted with a new key.", Style::default().fg(Color::White))),
+                    ];
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Key rotation (Rotate - Y, Later - N)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)));
+
+                    f.render_widget(paragraph, chunks[1]);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + 4,
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::ChangePassword {step, input_buffer, cursor_pos, error_message, ..} => {
                     let label = match step {
-                        0 => "Edit Website (account):",
-                        1 => "Edit Email/Username:",
-                        2 => "Edit Password: (# - generate safe password)",
-                        _ => "Updating...",
+                        0 => "Enter current password:",
+                        1 => "Enter new password:",
+                        2 => "Re-enter new password",
+                        _ => "Finito!",
                     };
 
-                    let current_value = match step {
-                        0 => account,
-                        1 => username,
-                        2 => password,
-                        _ => "",
-                    };
+                    let cursor_pos = std::cmp::min(*cursor_pos, input_buffer.len());
 
-                    let display_value = input_buffer;
+                    let before = &input_buffer[..cursor_pos];
+                    let cursor_char = input_buffer.chars().nth(cursor_pos).unwrap_or(' ');
+                    let after = if cursor_pos < input_buffer.len() {
+                        &input_buffer[cursor_pos + cursor_char.len_utf8()..]
+                    } else {
+                        ""
+                    };
 
-                    let mut lines = vec![
-                        Line::from(Span::styled(
-                            label,
+                    let spans = vec![
+                        Span::styled(before, Style::default().fg(Color::White)),
+                        Span::styled(
+                            cursor_char.to_string(),
                             Style::default()
-                                .fg(Color::Rgb(255, 60, 60))
+                                .fg(Color::Rgb(0, 255, 255))
+                                .bg(Color::Rgb(255, 60, 60))
                                 .add_modifier(Modifier::BOLD),
-                        )),
+                        ),
+                        Span::styled(after, Style::default().fg(Color::White)),
                     ];
 
-                    let cursor_pos = std::cmp::min(*cursor_pos, display_value.len());
+                    let lines = vec![
+                        Line::from(Span::styled(label, Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(spans),
+                    ];
 
-                    let mut spans = vec![];
-                    let before = &display_value[..cursor_pos];
-                    let cursor_char = display_value
-                        .chars()
-                        .nth(cursor_pos)
-                        .unwrap_or(' ');
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Changing master password (Cancel/Menu - Esc)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)));
 
-                    let after = if cursor_pos < display_value.len() {
-                        &display_value[cursor_pos + cursor_char.len_utf8()..]
+                    f.render_widget(paragraph, chunks[1]);
+
+                    let actual_pos = std::cmp::min(cursor_pos, input_buffer.len());
+                    let cursor_x = chunks[1].x + 1 + actual_pos as u16;
+                    let cursor_y = chunks[1].y + 2;
+                    f.set_cursor(cursor_x, cursor_y);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + 4,
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::RotationSettings { input_buffer, error_message, .. } => {
+                    let lines = vec![
+                        Line::from(Span::styled("Rotate keys every N days (0 - never):", Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(Span::styled(input_buffer.as_str(), Style::default().fg(Color::White))),
+                    ];
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Key rotation (Save - Enter, Rotate now - R, Menu - Esc)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)));
+
+                    f.render_widget(paragraph, chunks[1]);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + 4,
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::TwoFactor { enrollment: None, input_buffer, error_message, .. } => {
+                    let lines = vec![
+                        Line::from(Span::styled("Two-factor authentication is on.", Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(Span::styled("Logging in asks for a code from your authenticator app. Enter one to turn it off:", Style::default().fg(Color::White))),
+                        Line::from(Span::styled(input_buffer.as_str(), Style::default().fg(Color::White))),
+                    ];
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Two-factor authentication (Disable - Enter, Menu - Esc)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)));
+
+                    f.render_widget(paragraph, chunks[1]);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + 5,
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::TwoFactor { enrollment: Some((secret, qr_code)), input_buffer, error_message, .. } => {
+                    let mut lines = vec![
+                        Line::from(Span::styled("Scan the code with your authenticator app:", Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                    ];
+                    lines.extend(qr_code.lines().map(|line| Line::from(Span::styled(line, Style::default().fg(Color::White)))));
+                    lines.push(Line::from(Span::styled(format!("Or enter the key: {}", totp::encode_secret(secret)), Style::default().fg(Color::White))));
+                    lines.push(Line::from(Span::styled("Enter the code it shows:", Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))));
+                    lines.push(Line::from(Span::styled(input_buffer.as_str(), Style::default().fg(Color::White))));
+
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Two-factor authentication (Confirm - Enter, Menu - Esc)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)));
+
+                    f.render_widget(paragraph, chunks[1]);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + chunks[1].height.saturating_sub(3),
+                            width: chunks[1].width,
+                            height: 3.min(chunks[1].height),
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::RecoveryKey { words, .. } => {
+                    let lines = vec![
+                        Line::from(Span::styled("Your recovery key:", Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(Span::styled(words.as_str(), Style::default().fg(Color::White))),
+                        Line::from(""),
+                        Line::from(Span::styled("Write the words down and keep them safe. They are shown only now,", Style::default().fg(Color::White))),
+                        Line::from(Span::styled("and unlock your vault through Forgot password if you lose the master password.", Style::default().fg(Color::White))),
+                    ];
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Recovery key (Continue - Enter)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)))
+                        .wrap(Wrap { trim: false });
+
+                    f.render_widget(paragraph, chunks[1]);
+                }
+
+                AppState::ForgotPassword {step, input_buffer, cursor_pos, error_message, ..} => {
+                    let label = match step {
+                        0 => "Enter nickname:",
+                        1 => "Enter recovery key words:",
+                        2 => "Enter new password:",
+                        3 => "Re-enter new password",
+                        _ => "Finito!",
+                    };
+
+                    let cursor_pos = std::cmp::min(*cursor_pos, input_buffer.len());
+
+                    let before = &input_buffer[..cursor_pos];
+                    let cursor_char = input_buffer.chars().nth(cursor_pos).unwrap_or(' ');
+                    let after = if cursor_pos < input_buffer.len() {
+                        &input_buffer[cursor_pos + cursor_char.len_utf8()..]
                     } else {
                         ""
                     };
 
-                    spans.push(Span::styled(before, Style::default().fg(Color::White)));
-                    spans.push(Span::styled(
-                        cursor_char.to_string(),
-                        Style::default()
-                            .fg(Color::Rgb(0, 255, 255))
-                            .bg(Color::Rgb(255, 60, 60))
-                            .add_modifier(Modifier::BOLD),
-                    ));
-                    spans.push(Span::styled(after, Style::default().fg(Color::White)));
+                    let spans = vec![
+                        Span::styled(before, Style::default().fg(Color::White)),
+                        Span::styled(
+                            cursor_char.to_string(),
+                            Style::default()
+                                .fg(Color::Rgb(0, 255, 255))
+                                .bg(Color::Rgb(255, 60, 60))
+                                .add_modifier(Modifier::BOLD),
+                        ),
+                        Span::styled(after, Style::default().fg(Color::White)),
+                    ];
 
-                    lines.push(Line::from(spans));
+                    let lines = vec![
+                        Line::from(Span::styled(label, Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(spans),
+                    ];
+
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Forgot password (Cancel/Start - Esc)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)));
+
+                    f.render_widget(paragraph, chunks[1]);
+
+                    let actual_pos = std::cmp::min(cursor_pos, input_buffer.len());
+                    let cursor_x = chunks[1].x + 1 + actual_pos as u16;
+                    let cursor_y = chunks[1].y + 2;
+                    f.set_cursor(cursor_x, cursor_y);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + 4,
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::KeyFile {step, input_buffer, cursor_pos, error_message, ..} => {
+                    let label = match step {
+                        0 => "Enter master password:",
+                        1 => "Enter key file path (missing file is created, empty removes the key file):",
+                        _ => "Finito!",
+                    };
+
+                    let cursor_pos = std::cmp::min(*cursor_pos, input_buffer.len());
+
+                    let before = &input_buffer[..cursor_pos];
+                    let cursor_char = input_buffer.chars().nth(cursor_pos).unwrap_or(' ');
+                    let after = if cursor_pos < input_buffer.len() {
+                        &input_buffer[cursor_pos + cursor_char.len_utf8()..]
+                    } else {
+                        ""
+                    };
+
+                    let spans = vec![
+                        Span::styled(before, Style::default().fg(Color::White)),
+                        Span::styled(
+                            cursor_char.to_string(),
+                            Style::default()
+                                .fg(Color::Rgb(0, 255, 255))
+                                .bg(Color::Rgb(255, 60, 60))
+                                .add_modifier(Modifier::BOLD),
+                        ),
+                        Span::styled(after, Style::default().fg(Color::White)),
+                    ];
 
+                    let lines = vec![
+                        Line::from(Span::styled(label, Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(spans),
+                    ];
 
                     let paragraph = Paragraph::new(Text::from(lines))
-                        .block(Block::default().title("Edit Vault (Next - Enter, Cancel - Esc)").borders(Borders::ALL))
+                        .block(Block::default().title("Key file (Cancel/Menu - Esc)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)));
+
+                    f.render_widget(paragraph, chunks[1]);
+
+                    let actual_pos = std::cmp::min(cursor_pos, input_buffer.len());
+                    let cursor_x = chunks[1].x + 1 + actual_pos as u16;
+                    let cursor_y = chunks[1].y + 2;
+                    f.set_cursor(cursor_x, cursor_y);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + 4,
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::RecoveryCodes { codes, remaining, error_message, .. } => {
+                    let mut lines = vec![];
+                    // The count is not known after an error, the error box takes its place
+                    if codes.is_empty() && error_message.is_none() {
+                        lines.push(Line::from(Span::styled(format!("{} unused recovery codes left.", remaining), Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))));
+                        lines.push(Line::from(Span::styled("Codes are shown only when generated. New codes replace the old ones.", Style::default().fg(Color::White))));
+                    } else if !codes.is_empty() {
+                        lines.push(Line::from(Span::styled("Write these codes down, they will not be shown again:", Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))));
+                        lines.extend(codes.iter().map(|code| Line::from(Span::styled(code.as_str(), Style::default().fg(Color::White)))));
+                        lines.push(Line::from(Span::styled("Each code logs you in once in place of an authentication code.", Style::default().fg(Color::White))));
+                    }
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Recovery codes (Generate new - G, Menu - Esc)").borders(Borders::ALL))
                         .style(Style::default().fg(Color::Rgb(0, 255, 255)));
 
                     f.render_widget(paragraph, chunks[1]);
 
-                    let cursor_x = chunks[1].x + 1 + cursor_pos as u16;
-                    let cursor_y = chunks[1].y + 2;
-                    f.set_cursor(cursor_x, cursor_y);
-                }
-            }
-        })?;
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + chunks[1].height.saturating_sub(4),
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::Tampered { message, error_message, .. } => {
+                    let lines = vec![
+                        Line::from(Span::styled("!!! YOUR VAULT HAS BEEN TAMPERED WITH !!!", Style::default().fg(Color::Red).add_modifier(Modifier::BOLD | Modifier::SLOW_BLINK))),
+                        Line::from(""),
+                        Line::from(Span::styled(message.as_str(), Style::default().fg(Color::White).add_modifier(Modifier::BOLD))),
+                        Line::from(""),
+                        Line::from(Span::styled("Entries were removed, changed or restored from an older copy of the database outside of this application.", Style::default().fg(Color::White))),
+                        Line::from(Span::styled("Compare your vault with a backup before you trust it.", Style::default().fg(Color::White))),
+                    ];
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(
+                            Block::default()
+                                .title("Integrity warning (Trust the vault as it is now - Enter, Logout - Esc)")
+                                .borders(Borders::ALL)
+                                .border_type(BorderType::Thick)
+                                .border_style(Style::default().fg(Color::Red)),
+                        )
+                        .wrap(Wrap { trim: true });
+
+                    f.render_widget(paragraph, chunks[1]);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + chunks[1].height.saturating_sub(4),
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::UnreadableEntries { count, .. } => {
+                    let lines = vec![
+                        Line::from(Span::styled(format!("{} of your entries could not be decrypted.", count), Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(""),
+                        Line::from(Span::styled("They were written with a key this vault no longer has, for example by an older version that lost track of it.", Style::default().fg(Color::White))),
+                        Line::from(Span::styled("They are left out of your vault list, but kept unchanged in case that key turns up again.", Style::default().fg(Color::White))),
+                    ];
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(
+                            Block::default()
+                                .title("Unreadable entries (Continue - Enter, Logout - Esc)")
+                                .borders(Borders::ALL)
+                                .border_type(BorderType::Thick)
+                                .border_style(Style::default().fg(Color::Rgb(255, 60, 60))),
+                        )
+                        .wrap(Wrap { trim: true });
+
+                    f.render_widget(paragraph, chunks[1]);
+                }
+
+                AppState::RememberDevice { remembered, error_message, .. } => {
+                    let status = if *remembered {
+                        "This device is remembered and logs in without the master password."
+                    } else {
+                        "This device asks for the master password on every login."
+                    };
+                    let mut lines = vec![
+                        Line::from(Span::styled(status, Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(Span::styled("Anyone using this device account can then open your vault. Changing the master password forgets every device.", Style::default().fg(Color::White))),
+                    ];
+                    if let Some(msg) = error_message {
+                        lines.push(Line::from(Span::styled(msg.as_str(), Style::default().fg(Color::Red))));
+                    }
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Remember this device (Remember/Forget - Enter, Menu - Esc)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)))
+                        .wrap(Wrap { trim: true });
+
+                    f.render_widget(paragraph, chunks[1]);
+                }
+
+                AppState::DeleteAccount { username, step, input_buffer, cursor_pos, error_message, .. } => {
+                    let label = match step {
+                        0 => format!("Type your username ({}) to confirm:", username),
+                        _ => "Enter master password:".to_string(),
+                    };
+
+                    let cursor_pos = std::cmp::min(*cursor_pos, input_buffer.len());
+
+                    let before = &input_buffer[..cursor_pos];
+                    let cursor_char = input_buffer.chars().nth(cursor_pos).unwrap_or(' ');
+                    let after = if cursor_pos < input_buffer.len() {
+                        &input_buffer[cursor_pos + cursor_char.len_utf8()..]
+                    } else {
+                        ""
+                    };
+
+                    let spans = vec![
+                        Span::styled(before, Style::default().fg(Color::White)),
+                        Span::styled(
+                            cursor_char.to_string(),
+                            Style::default()
+                                .fg(Color::Rgb(0, 255, 255))
+                                .bg(Color::Rgb(255, 60, 60))
+                                .add_modifier(Modifier::BOLD),
+                        ),
+                        Span::styled(after, Style::default().fg(Color::White)),
+                    ];
+
+                    let lines = vec![
+                        Line::from(Span::styled(label, Style::default().fg(Color::Rgb(255, 60, 60)).add_modifier(Modifier::BOLD))),
+                        Line::from(spans),
+                        Line::from(""),
+                        Line::from(Span::styled(
+                            "Your account and every vault in it are deleted for good. This cannot be undone.",
+                            Style::default().fg(Color::White),
+                        )),
+                    ];
+
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(
+                            Block::default()
+                                .title("Delete my account (Next - Enter, Cancel/Menu - Esc)")
+                                .borders(Borders::ALL)
+                                .border_style(Style::default().fg(Color::Red)),
+                        )
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)));
+
+                    f.render_widget(paragraph, chunks[1]);
+
+                    let cursor_x = chunks[1].x + 1 + cursor_pos as u16;
+                    let cursor_y = chunks[1].y + 2;
+                    f.set_cursor(cursor_x, cursor_y);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + 6,
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+
+                AppState::EditVault { step, input_buffer, cursor_pos, error_message, ..} => {
+                    let label = match step {
+                        0 => "Edit Website (account):",
+                        1 => "Edit Email/Username:",
+                        2 => "Edit Password: (# - generate safe password)",
+                        3 => "Edit authenticator otpauth:// URI (empty - none):",
+                        _ => "Updating...",
+                    };
+
+                    let display_value = input_buffer;
+
+                    let mut lines = vec![
+                        Line::from(Span::styled(
+                            label,
+                            Style::default()
+                                .fg(Color::Rgb(255, 60, 60))
+                                .add_modifier(Modifier::BOLD),
+                        )),
+                    ];
+
+                    let cursor_pos = std::cmp::min(*cursor_pos, display_value.len());
+
+                    let mut spans = vec![];
+                    let before = &display_value[..cursor_pos];
+                    let cursor_char = display_value
+                        .chars()
+                        .nth(cursor_pos)
+                        .unwrap_or(' ');
+
+                    let after = if cursor_pos < display_value.len() {
+                        &display_value[cursor_pos + cursor_char.len_utf8()..]
+                    } else {
+                        ""
+                    };
+
+                    spans.push(Span::styled(before, Style::default().fg(Color::White)));
+                    spans.push(Span::styled(
+                        cursor_char.to_string(),
+                        Style::default()
+                            .fg(Color::Rgb(0, 255, 255))
+                            .bg(Color::Rgb(255, 60, 60))
+                            .add_modifier(Modifier::BOLD),
+                    ));
+                    spans.push(Span::styled(after, Style::default().fg(Color::White)));
+
+                    lines.push(Line::from(spans));
+
+
+                    let paragraph = Paragraph::new(Text::from(lines))
+                        .block(Block::default().title("Edit Vault (Next - Enter, Cancel - Esc)").borders(Borders::ALL))
+                        .style(Style::default().fg(Color::Rgb(0, 255, 255)));
+
+                    f.render_widget(paragraph, chunks[1]);
+
+                    let cursor_x = chunks[1].x + 1 + cursor_pos as u16;
+                    let cursor_y = chunks[1].y + 2;
+                    f.set_cursor(cursor_x, cursor_y);
+
+                    if let Some(msg) = error_message {
+                        let error_paragraph = Paragraph::new(Text::from(Line::from(Span::styled(
+                            msg.as_str(),
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        ))))
+                            .block(Block::default().borders(Borders::ALL).title("Error"));
+
+                        let error_rect = Rect {
+                            x: chunks[1].x,
+                            y: chunks[1].y + 4,
+                            width: chunks[1].width,
+                            height: 3,
+                        };
+
+                        f.render_widget(error_paragraph, error_rect);
+                    }
+                }
+            }
+        })?;
+
+        //functionality of scenes
+        if event::poll(std::time::Duration::from_millis(200))? {
+            if let Event::Key(KeyEvent { code, kind: KeyEventKind::Press, .. }) = event::read()? {
+                let selected = list_state.selected().unwrap_or(0);
+
+                match state {
+                    AppState::Start => {
+                        match code {
+                            KeyCode::Down => {
+                                let new_index = (selected + 1).min(START_ITEMS.len() - 1);
+                                list_state.select(Some(new_index));
+                            }
+                            KeyCode::Up => {
+                                let new_index = selected.saturating_sub(1);
+                                list_state.select(Some(new_index));
+                            }
+                            KeyCode::Enter => match selected {
+                                0 => {
+                                    *state = AppState::Login {
+                                        step: 0,
+                                        username: String::new(),
+                                        password: SecretString::default(),
+                                        input_buffer: SecretString::with_capacity(INPUT_CAPACITY),
+                                        cursor_pos: 0,
+                                        error_message: None,
+                                        error_time: None,
+                                        key_file: None,
+                                        retry_at: None,
+                                        pending_session: None,
+                                    };
+                                },
+                                1 => {
+                                    *state = AppState::Register {
+                                        step: 0,
+                                        username: String::new(),
+                                        password: SecretString::default(),
+                                        password2: SecretString::default(),
+                                        input_buffer: SecretString::with_capacity(INPUT_CAPACITY),
+                                        cursor_pos: 0,
+                                        error_message: None,
+                                        error_time: None,
+                                    };
+                                },
+                                2 => {
+                                    *state = AppState::ForgotPassword {
+                                        step: 0,
+                                        username: String::new(),
+                                        recovery_words: SecretString::default(),
+                                        password: SecretString::default(),
+                                        password2: SecretString::default(),
+                                        input_buffer: SecretString::with_capacity(INPUT_CAPACITY),
+                                        cursor_pos: 0,
+                                        error_message: None,
+                                        error_time: None,
+                                    };
+                                },
+                                3 => return Ok(()),
+                                _ => {}
+                            },
+                            KeyCode::Char('q') => return Ok(()),
+                            _ => {}
+                        }
+                    }
+
+                    AppState::Register {step, username, password, password2, input_buffer, cursor_pos, error_message, error_time} => {
+                        match code {
+                            KeyCode::Char(c)
+                                if *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.insert(*cursor_pos, c);
+                                    *cursor_pos += 1;
+                                }
+                            KeyCode::Backspace
+                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.remove(*cursor_pos - 1);
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Left
+                                if *cursor_pos > 0 => {
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Right
+                                if *cursor_pos < input_buffer.len() => {
+                                    *cursor_pos += 1;
+                                }
+                            KeyCode::Enter => {
+                                match *step {
+                                    0 => {
+                                        let mut stmt = conn.prepare("SELECT 1 FROM users WHERE username = ?1")?;
+                                        let exists = stmt.exists([input_buffer.as_str()])?;
+
+                                        if exists {
+                                            *error_message = Some(password_manager_lib::Error::UsernameUnavailable.to_string());
+                                            *error_time = Some(std::time::Instant::now());
+                                            input_buffer.clear();
+                                            *cursor_pos = 0;
+                                        } else {
+                                            *username = input_buffer.to_string();
+                                            input_buffer.clear();
+                                            *cursor_pos = 0;
+                                            *step = 1;
+                                        }
+                                    }
+                                    1 => {
+                                        *password = input_buffer.clone();
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        *step = 2;
+                                    }
+                                    2 => {
+                                        *password2 = input_buffer.clone();
+                                        if password == password2 {
+                                            input_buffer.clear();
+                                            input_buffer.push_str(&unlock_time.unwrap_or(DEFAULT_UNLOCK_TIME).as_millis().to_string());
+                                            *cursor_pos = input_buffer.len();
+                                            *step = 3;
+                                        }
+
+                                        else {
+                                            *error_message = Some("Passwords do not match".to_string());
+                                            *error_time = Some(std::time::Instant::now());
+                                            *step = 1;
+                                        }
+                                    }
+                                    3 => {
+                                        if let Ok(millis) = input_buffer.trim().parse::<u64>() {
+                                            // Argon2 is measured on this machine, once for the default
+                                            let target = Duration::from_millis(millis);
+                                            let params = if target == DEFAULT_UNLOCK_TIME { calibrated_params() } else { calibrate(target) };
+                                            match register_user_with_params(conn, username, password, &params, cipher) {
+                                                Ok(mut new_session) => {
+                                                    input_buffer.clear();
+                                                    *cursor_pos = 0;
+                                                    match create_recovery_key(conn, &mut new_session) {
+                                                        Ok(words) => {
+                                                            *state = AppState::RecoveryKey { user_id: new_session.user_id, words };
+                                                            key_file = None;
+                                                            session = Some(new_session);
+                                                        }
+                                                        // The account exists, only the way back in without the password is missing
+                                                        Err(err) => {
+                                                            *error_message = Some(format!("Registered, but the recovery key was not created: {}", err));
+                                                            *error_time = Some(std::time::Instant::now());
+                                                            *step = 0;
+                                                        }
+                                                    }
+                                                }
+                                                Err(err) => {
+                                                    *error_message = Some(format!("Failed to register: {}", err));
+                                                    *error_time = Some(std::time::Instant::now());
+                                                    input_buffer.clear();
+                                                    *cursor_pos = 0;
+                                                    *step = 0;
+                                                }
+                                            }
+                                        }
+
+                                        else {
+                                            *error_message = Some("Unlock time has to be a whole number of milliseconds".to_string());
+                                            *error_time = Some(std::time::Instant::now());
+                                        }
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            KeyCode::Esc => {
+                                input_buffer.clear();
+                                *state = AppState::Start;
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    AppState::Login {step, username, password, input_buffer, cursor_pos, error_message, error_time, key_file: login_key_file, retry_at, pending_session} => {
+                        match code {
+                            KeyCode::Char(c)
+                                if *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.insert(*cursor_pos, c);
+                                    *cursor_pos += 1;
+                                }
+                            KeyCode::Backspace
+                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.remove(*cursor_pos - 1);
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Left
+                                if *cursor_pos > 0 => {
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Right
+                                if *cursor_pos < input_buffer.len() => {
+                                    *cursor_pos += 1;
+                                }
+                            KeyCode::Enter => {
+                                match *step {
+                                    // Whether the user exists is only revealed together with the password check
+                                    0 => {
+                                        *username = input_buffer.to_string();
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        // A stale or unreadable remembered key falls back to the password
+                                        match login_remembered(conn, key_store, username) {
+                                            Ok(Some(new_session)) if totp_enabled(conn, new_session.user_id)? => {
+                                                *pending_session = Some(new_session);
+                                                *step = 3;
+                                            }
+                                            Ok(Some(new_session)) => {
+                                                key_file = None;
+                                                *state = logged_in_state(conn, new_session.user_id)?;
+                                                session = Some(new_session);
+                                            }
+                                            _ => *step = 1,
+                                        }
+                                    }
+                                    1 | 2 => {
+                                        let read_key_file = match *step {
+                                            1 => {
+                                                *password = input_buffer.clone();
+                                                Ok(None)
+                                            }
+                                            _ => std::fs::read(input_buffer.as_str()).map(|contents| Some(SecretKey::new(contents))),
+                                        };
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        match read_key_file {
+                                            Ok(read_key_file) => {
+                                                *login_key_file = read_key_file;
+                                                match login_user_with_key_file(conn, username, password, login_key_file.as_deref()) {
+                                                    Ok(new_session) if totp_enabled(conn, new_session.user_id)? => {
+                                                        *pending_session = Some(new_session);
+                                                        *step = 3;
+                                                    }
+                                                    Ok(new_session) => {
+                                                        key_file = login_key_file.take();
+                                                        *state = logged_in_state(conn, new_session.user_id)?;
+                                                        session = Some(new_session);
+                                                    }
+                                                    Err(password_manager_lib::Error::KeyFileRequired) => {
+                                                        *step = 2;
+                                                    }
+                                                    Err(password_manager_lib::Error::Throttled(seconds)) => {
+                                                        *retry_at = Some(std::time::Instant::now() + Duration::from_secs(seconds));
+                                                        *error_message = None;
+                                                        *step = 1;
+                                                    }
+                                                    Err(err) => {
+                                                        *error_message = Some(err.to_string());
+                                                        *error_time = Some(std::time::Instant::now());
+                                                        *step = 1;
+                                                    }
+                                                }
+                                            }
+                                            Err(err) => {
+                                                *error_message = Some(format!("Cannot read key file: {}", err));
+                                                *error_time = Some(std::time::Instant::now());
+                                            }
+                                        }
+                                    }
+                                    3 => {
+                                        let pending = pending_session.as_ref().ok_or("No pending login")?;
+                                        let verified = if input_buffer.len() == totp::DIGITS as usize && input_buffer.chars().all(|c| c.is_ascii_digit()) {
+                                            verify_totp(conn, pending, input_buffer)
+                                        } else {
+                                            use_recovery_code(conn, pending, input_buffer)
+                                        };
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        match verified {
+                                            Ok(()) => {
+                                                let new_session = pending_session.take().ok_or("No pending login")?;
+                                                key_file = login_key_file.take();
+                                                *state = logged_in_state(conn, new_session.user_id)?;
+                                                session = Some(new_session);
+                                            }
+                                            Err(password_manager_lib::Error::Authentication) => {
+                                                *error_message = Some("Invalid authentication or recovery code".to_string());
+                                                *error_time = Some(std::time::Instant::now());
+                                            }
+                                            Err(err) => {
+                                                *error_message = Some(err.to_string());
+                                                *error_time = Some(std::time::Instant::now());
+                                            }
+                                        }
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            KeyCode::Esc => {
+                                input_buffer.clear();
+                                *state = AppState::Start;
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    AppState::Menu {user_id} => {
+                        match code {
+                            KeyCode::Down => {
+                                let new_index = (selected + 1).min(MENU_ITEMS.len() - 1);
+                                list_state.select(Some(new_index));
+                            }
+                            KeyCode::Up => {
+                                let new_index = selected.saturating_sub(1);
+                                list_state.select(Some(new_index));
+                            }
+                            KeyCode::Enter => match selected {
+                                0 => {
+                                    *state = AppState::CreateAccount {
+                                        user_id: *user_id,
+                                        step: 0,
+                                        account: String::new(),
+                                        username: String::new(),
+                                        password: SecretString::default(),
+                                        input_buffer: SecretString::with_capacity(INPUT_CAPACITY),
+                                        cursor_pos: 0,
+                                        error_message: None,
+                                    };
+                                }
+                                1 => { *state = AppState::SearchVault {
+                                    user_id: {*user_id},
+                                    input_buffer: String::new(),}; }
+                                2 => {
+                                    let session = session.as_ref().ok_or("No active session")?;
+                                    let (mut vaults, error_message) = match list_entries(conn, session) {
+                                        Ok(vaults) => (vaults, None),
+                                        Err(err) => (Vec::new(), Some(format!("Failed to load vaults: {}", err))),
+                                    };
+                                    let show_headers = !vaults.is_empty();
+                                    sort_entries(&mut vaults);
+
+                                    *state = AppState::ShowAllVaults {
+                                        user_id: *user_id,
+                                        entries: vaults,
+                                        empty_message: NO_VAULTS,
+                                        scroll: 0,
+                                        selected: 0,
+                                        show_headers,
+                                        error_message,
+                                    };
+                                }
+                                3 => {
+                                    let (input_buffer, error_message) = match get_rotation_interval(conn, *user_id) {
+                                        Ok(days) => (days.to_string(), None),
+                                        Err(err) => (String::new(), Some(err.to_string())),
+                                    };
+                                    *state = AppState::RotationSettings { user_id: *user_id, input_buffer, error_message };
+                                }
+                                4 => {
+                                    *state = AppState::ChangePassword {
+                                        user_id: *user_id,
+                                        step: 0,
+                                        old_password: SecretString::default(),
+                                        password: SecretString::default(),
+                                        password2: SecretString::default(),
+                                        input_buffer: SecretString::with_capacity(INPUT_CAPACITY),
+                                        cursor_pos: 0,
+                                        error_message: None,
+                                        error_time: None,
+                                    };
+                                }
+                                5 => {
+                                    *state = two_factor_state(conn, *user_id, None)?;
+                                }
+                                6 => {
+                                    let (remaining, error_message) = match recovery_codes_left(conn, *user_id) {
+                                        Ok(remaining) => (remaining, None),
+                                        Err(err) => (0, Some(err.to_string())),
+                                    };
+                                    *state = AppState::RecoveryCodes { user_id: *user_id, codes: Vec::new(), remaining, error_message };
+                                }
+                                7 => {
+                                    *state = AppState::KeyFile {
+                                        user_id: *user_id,
+                                        step: 0,
+                                        password: SecretString::default(),
+                                        input_buffer: SecretString::with_capacity(INPUT_CAPACITY),
+                                        cursor_pos: 0,
+                                        error_message: None,
+                                        error_time: None,
+                                    };
+                                }
+                                8 => {
+                                    let username: String = conn.query_row("SELECT username FROM users WHERE id = ?1", [*user_id], |row| row.get(0))?;
+                                    let (remembered, error_message) = match device_remembered(key_store, &username) {
+                                        Ok(remembered) => (remembered, None),
+                                        Err(err) => (false, Some(err.to_string())),
+                                    };
+                                    *state = AppState::RememberDevice { user_id: *user_id, username, remembered, error_message };
+                                }
+                                9 => {
+                                    let username: String = conn.query_row("SELECT username FROM users WHERE id = ?1", [*user_id], |row| row.get(0))?;
+                                    *state = AppState::DeleteAccount {
+                                        user_id: *user_id,
+                                        username,
+                                        step: 0,
+                                        input_buffer: SecretString::with_capacity(INPUT_CAPACITY),
+                                        cursor_pos: 0,
+                                        error_message: None,
+                                    };
+                                }
+                                10 => {
+                                    session = None;
+                                    key_file = None;
+                                    *state = AppState::Start;
+                                }
+                                _ => {}
+                            },
+                            KeyCode::Char('q') => return Ok(()),
+                            _ => {}
+                        }
+                    }
+
+                    AppState::ShowAllVaults {user_id, scroll, selected, entries, show_headers, error_message, ..} => {
+                        match code {
+                            KeyCode::Esc => {
+                                *state = AppState::Menu {user_id: *user_id};
+                            }
+                            KeyCode::Down
+                                if *selected < entries.len().saturating_sub(1) => {
+                                    *selected += 1;
+
+                                    let mut line_index = 0;
+                                    let mut last_letter: Option<char> = None;
+
+                                    for (i, entry) in entries.iter().enumerate() {
+                                        let first_letter = entry.account.chars().next().unwrap_or('?').to_ascii_uppercase();
+                                        if Some(first_letter) != last_letter {
+                                            line_index += 1;
+                                            last_letter = Some(first_letter);
+                                        }
+                                        if i == *selected {
+                                            break;
+                                        }
+                                        line_index += 1;
+                                    }
+
+                                    let terminal_height = terminal.size()?.height.saturating_sub(4) as usize;
+                                    if line_index >= *scroll as usize + terminal_height {
+                                        *scroll = (line_index + 1 - terminal_height) as u16;
+                                    }
+                                }
+                            KeyCode::Up
+                                if *selected > 0 => {
+                                    *selected -= 1;
+
+                                    let mut line_index = 0;
+                                    let mut last_letter: Option<char> = None;
+
+                                    for (i, entry) in entries.iter().enumerate() {
+                                        let first_letter = entry.account.chars().next().unwrap_or('?').to_ascii_uppercase();
+                                        if Some(first_letter) != last_letter {
+                                            line_index += 1;
+                                            last_letter = Some(first_letter);
+                                        }
+                                        if i == *selected {
+                                            break;
+                                        }
+                                        line_index += 1;
+                                    }
+
+                                    if line_index < *scroll as usize {
+                                        *scroll = line_index as u16;
+                                    }
+                                }
+                            KeyCode::Enter => {
+                                let Some(selected_entry) = entries.get(*selected) else {
+                                    continue;
+                                };
+                                let session = session.as_ref().ok_or("No active session")?;
+                                let entry = match get_entry(conn, session, selected_entry.id) {
+                                    Ok(entry) => entry,
+                                    Err(err) => {
+                                        *error_message = Some(err.to_string());
+                                        continue;
+                                    }
+                                };
+
+                                *state = AppState::ViewVaultDetail {
+                                    user_id: *user_id,
+                                    entry,
+                                    previous_entries: entries.clone(),
+                                    previous_scroll: *scroll,
+                                    previous_selected: *selected,
+                                    scroll: 0,
+                                    previous_show_headers: *show_headers,
+                                    copy_message: None,
+                                    obscure_password: true,
+                                    error_message: None,
+                                };
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    AppState::ViewVaultDetail {
+                        user_id,
+                        entry,
+                        previous_entries,
+                        previous_scroll,
+                        previous_selected,
+                        scroll,
+                        previous_show_headers,
+                        obscure_password,
+                        error_message,
+                        ..
+                    } => {
+                        match code {
+                            KeyCode::Esc => {
+                                *state = AppState::ShowAllVaults {
+                                    user_id: *user_id,
+                                    entries: previous_entries.clone(),
+                                    empty_message: NO_RESULTS,
+                                    scroll: *previous_scroll,
+                                    selected: *previous_selected,
+                                    show_headers: *previous_show_headers,
+                                    error_message: None,
+                                };
+                            }
+                            KeyCode::Down => {
+                                let content_lines: u16 = if entry.otp.is_some() { 4 * 3 } else { 3 * 3 };
+                                let visible_lines = terminal.size()?.height.saturating_sub(4);
+
+                                let max_scroll = content_lines.saturating_sub(visible_lines);
+
+                                if *scroll < max_scroll {
+                                    *scroll += 1;
+                                }
+                            }
+                            KeyCode::Up
+                                if *scroll > 0 => {
+                                    *scroll -= 1;
+                                }
+                            KeyCode::Char('d') => {
+                                if let Err(err) = delete_entry(conn, session.as_ref().ok_or("No active session")?, entry.id) {
+                                    *error_message = Some(format!("Failed to delete: {}", err));
+                                    continue;
+                                }
+                                let mut new_entries = previous_entries.clone();
+                                new_entries.retain(|previous| previous.id != entry.id);
+
+                                *state = AppState::ShowAllVaults {
+                                    user_id: *user_id,
+                                    entries: new_entries,
+                                    empty_message: NO_RESULTS,
+                                    scroll: *previous_scroll,
+                                    selected: 0,
+                                    show_headers: *previous_show_headers,
+                                    error_message: None,
+                                };
+                            }
+                            KeyCode::Char('e') => {
+                                *state = AppState::EditVault {
+                                    user_id: *user_id,
+                                    step: 0,
+                                    entry: entry.clone(),
+                                    input_buffer: entry.account.as_str().into(),
+                                    temp_account: entry.account.clone(),
+                                    temp_username: entry.username.clone(),
+                                    temp_password: entry.password.clone(),
+                                    previous_entries: previous_entries.clone(),
+                                    previous_scroll: *previous_scroll,
+                                    previous_selected: *previous_selected,
+                                    previous_show_headers: *previous_show_headers,
+                                    cursor_pos: entry.account.len(),
+                                    error_message: None,
+                                };
+                            }
+                            KeyCode::Char('u') => {
+                                if let Ok(mut cb) = Clipboard::new() {
+                                    cb.set_text(entry.username.clone()).ok();
+                                }
+
+                                *state = AppState::ViewVaultDetail {
+                                    user_id: *user_id,
+                                    entry: entry.clone(),
+                                    previous_entries: previous_entries.clone(),
+                                    previous_scroll: *previous_scroll,
+                                    previous_selected: *previous_selected,
+                                    scroll: *scroll,
+                                    previous_show_headers: *previous_show_headers,
+                                    copy_message: Some(("Email/Username copied!".to_string(), std::time::Instant::now())),
+                                    obscure_password: *obscure_password,
+                                    error_message: None,
+                                };
+                            }
+                            KeyCode::Char('p') => {
+                                if let Ok(mut cb) = Clipboard::new() {
+                                    cb.set_text(entry.password.as_str()).ok();
+                                }
+
+                                *state = AppState::ViewVaultDetail {
+                                    user_id: *user_id,
+                                    entry: entry.clone(),
+                                    previous_entries: previous_entries.clone(),
+                                    previous_scroll: *previous_scroll,
+                                    previous_selected: *previous_selected,
+                                    scroll: *scroll,
+                                    previous_show_headers: *previous_show_headers,
+                                    copy_message: Some(("Password copied!".to_string(), std::time::Instant::now())),
+                                    obscure_password: *obscure_password,
+                                    error_message: None,
+                                };
+                            }
+                            KeyCode::Char('o') if entry.otp.is_some() => {
+                                let current = entry.otp.as_ref().ok_or("No authenticator")?;
+                                let code = current.code(totp::unix_time());
+                                // An HOTP code is spent once handed out, the next copy gets the following one
+                                if let OtpKind::Hotp { .. } = current.kind {
+                                    let next = VaultEntry { otp: Some(current.next_counter()), ..entry.clone() };
+                                    match update_entry(conn, session.as_ref().ok_or("No active session")?, &next) {
+                                        Ok(updated) => *entry = updated,
+                                        // Not handed out, as the same code would be copied again next time
+                                        Err(err) => {
+                                            *error_message = Some(format!("Failed to advance the counter: {}", err));
+                                            continue;
+                                        }
+                                    }
+                                }
+                                if let Ok(mut cb) = Clipboard::new() {
+                                    cb.set_text(code).ok();
+                                }
+
+                                *state = AppState::ViewVaultDetail {
+                                    user_id: *user_id,
+                                    entry: entry.clone(),
+                                    previous_entries: previous_entries.clone(),
+                                    previous_scroll: *previous_scroll,
+                                    previous_selected: *previous_selected,
+                                    scroll: *scroll,
+                                    previous_show_headers: *previous_show_headers,
+                                    copy_message: Some(("Code copied!".to_string(), std::time::Instant::now())),
+                                    obscure_password: *obscure_password,
+                                    error_message: None,
+                                };
+                            }
+                            KeyCode::Char('s') => {
+                                *state = AppState::ViewVaultDetail {
+                                    user_id: *user_id,
+                                    entry: entry.clone(),
+                                    previous_entries: previous_entries.clone(),
+                                    previous_scroll: *previous_scroll,
+                                    previous_selected: *previous_selected,
+                                    scroll: *scroll,
+                                    previous_show_headers: *previous_show_headers,
+                                    copy_message: None,
+                                    obscure_password: !*obscure_password,
+                                    error_message: None,
+                                };
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    AppState::SearchVault {user_id, input_buffer } => {
+                        match code {
+                            KeyCode::Char(c) => input_buffer.push(c),
+                            KeyCode::Backspace => { input_buffer.pop(); }
+                            KeyCode::Enter => {
+                                if input_buffer.trim().is_empty() {
+                                    continue;
+                                }
+                                
+                                let session = session.as_ref().ok_or("No active session")?;
+                                let (mut entries, error_message) = match search_entries(conn, session, input_buffer) {
+                                    Ok(entries) => (entries, None),
+                                    Err(err) => (Vec::new(), Some(format!("Failed to search: {}", err))),
+                                };
+                                sort_entries(&mut entries);
 
-        //functionality of scenes
-        if event::poll(std::time::Duration::from_millis(200))? {
-            if let Event::Key(KeyEvent { code, kind: KeyEventKind::Press, .. }) = event::read()? {
-                let selected = list_state.selected().unwrap_or(0);
+                                *state = AppState::ShowAllVaults {
+                                    user_id: *user_id,
+                                    entries,
+                                    empty_message: NO_RESULTS,
+                                    scroll: 0,
+                                    selected: 0,
+                                    show_headers: false,
+                                    error_message,
+                                };
+                            }
+                            KeyCode::Esc => {
+                                *state = AppState::Menu{user_id: *user_id,};
+                            }
+                            _ => {}
+                        }
+                    }
 
-                match state {
-                    AppState::Start => {
+                    AppState::RotateKeys { user_id, error_message } => {
                         match code {
-                            KeyCode::Down => {
-                                let new_index = (selected + 1).min(MENU_ITEMS.len() - 1);
-                                list_state.select(Some(new_index));
+                            KeyCode::Char('y') => {
+                                match rotate_keys(conn, session.as_mut().ok_or("No active session")?) {
+                                    Ok(()) => *state = AppState::Menu { user_id: *user_id },
+                                    Err(err) => *error_message = Some(format!("Failed to rotate keys: {}", err)),
+                                }
                             }
-                            KeyCode::Up => {
-                                let new_index = selected.saturating_sub(1);
-                                list_state.select(Some(new_index));
+                            KeyCode::Char('n') | KeyCode::Esc => {
+                                *state = AppState::Menu { user_id: *user_id };
                             }
-                            KeyCode::Enter => match selected {
-                                0 => {
-                                    *state = AppState::Login {
-                                        step: 0,
-                                        username: String::new(),
-                                        password: String::new(),
-                                        input_buffer: String::new(),
-                                        cursor_pos: 0,
-                                        error_message: None,
-                                        error_time: None,
-                                    };
-                                },
-                                1 => {
-                                    *state = AppState::Register {
-                                        step: 0,
-                                        username: String::new(),
-                                        password: String::new(),
-                                        password2: String::new(),
-                                        input_buffer: String::new(),
-                                        cursor_pos: 0,
-                                        error_message: None,
-                                        error_time: None,
-                                    };
-                                },
-                                2 => return Ok(()),
-                                _ => {}
-                            },
-                            KeyCode::Char('q') => return Ok(()),
                             _ => {}
                         }
                     }
 
-                    AppState::Register {step, username, password, password2, input_buffer, cursor_pos, error_message, error_time} => {
+                    AppState::ChangePassword {user_id, step, old_password, password, password2, input_buffer, cursor_pos, error_message, error_time} => {
                         match code {
-                            KeyCode::Char(c) => {
-                                if *cursor_pos <= input_buffer.len() {
+                            KeyCode::Char(c)
+                                if *cursor_pos <= input_buffer.len() => {
                                     input_buffer.insert(*cursor_pos, c);
                                     *cursor_pos += 1;
                                 }
-                            }
-                            KeyCode::Backspace => {
-                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() {
+                            KeyCode::Backspace
+                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() => {
                                     input_buffer.remove(*cursor_pos - 1);
                                     *cursor_pos -= 1;
                                 }
-                            }
-                            KeyCode::Left => {
-                                if *cursor_pos > 0 {
+                            KeyCode::Left
+                                if *cursor_pos > 0 => {
                                     *cursor_pos -= 1;
                                 }
-                            }
-                            KeyCode::Right => {
-                                if *cursor_pos < input_buffer.len() {
+                            KeyCode::Right
+                                if *cursor_pos < input_buffer.len() => {
                                     *cursor_pos += 1;
                                 }
-                            }
                             KeyCode::Enter => {
                                 match *step {
                                     0 => {
-                                        let mut stmt = conn.prepare("SELECT 1 FROM users WHERE username = ?1")?;
-                                        let exists = stmt.exists([input_buffer.as_str()])?;
-
-                                        if exists {
-                                            *error_message = Some("Username already taken".to_string());
-                                            *error_time = Some(std::time::Instant::now());
-                                            input_buffer.clear();
-                                            *cursor_pos = 0;
-                                        } else {
-                                            *username = input_buffer.clone();
-                                            input_buffer.clear();
-                                            *cursor_pos = 0;
-                                            *step = 1;
-                                        }
+                                        *old_password = input_buffer.clone();
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        *step = 1;
                                     }
                                     1 => {
                                         *password = input_buffer.clone();
@@ -792,443 +2207,409 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                                     }
                                     2 => {
                                         *password2 = input_buffer.clone();
-                                        if password == password2 {
-                                            if let Err(err) = register_user(conn, username, password) {
-                                                *error_message = Some(format!("Failed to register: {}", err));
-                                                *error_time = Some(std::time::Instant::now());
-                                                *step = 0;
-                                            } else {
-                                                match get_user_id(conn, username) {
-                                                    Ok(user_id) => {
-                                                        input_buffer.clear();
-                                                        *cursor_pos = 0;
-                                                        *state = AppState::Menu { user_id };
-                                                    }
-                                                    Err(err) => {
-                                                        *error_message = Some(format!("Failed to get user ID: {}", err));
-                                                        *error_time = Some(std::time::Instant::now());
-                                                        *step = 0;
-                                                    }
-                                                }
-                                            }
-                                        }
-
-                                        else {
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        if password != password2 {
                                             *error_message = Some("Passwords do not match".to_string());
                                             *error_time = Some(std::time::Instant::now());
                                             *step = 1;
+                                        } else {
+                                            match change_master_password_with_key_file(conn, *user_id, old_password, password, key_file.as_deref()) {
+                                                Ok(new_session) => {
+                                                    *state = AppState::Menu { user_id: *user_id };
+                                                    session = Some(new_session);
+                                                }
+                                                Err(err) => {
+                                                    *error_message = Some(match err {
+                                                        password_manager_lib::Error::Authentication => "Current password is incorrect".to_string(),
+                                                        err => err.to_string(),
+                                                    });
+                                                    *error_time = Some(std::time::Instant::now());
+                                                    *step = 0;
+                                                }
+                                            }
                                         }
                                     }
                                     _ => {}
                                 }
                             }
                             KeyCode::Esc => {
-                                input_buffer.clear();
-                                *state = AppState::Start;
+                                *state = AppState::Menu { user_id: *user_id };
                             }
                             _ => {}
                         }
                     }
 
-                    AppState::Login {step, username, password, input_buffer, cursor_pos, error_message, error_time} => {
+                    AppState::RotationSettings { user_id, input_buffer, error_message } => {
                         match code {
-                            KeyCode::Char(c) => {
-                                if *cursor_pos <= input_buffer.len() {
-                                    input_buffer.insert(*cursor_pos, c);
-                                    *cursor_pos += 1;
-                                }
-                            }
-                            KeyCode::Backspace => {
-                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() {
-                                    input_buffer.remove(*cursor_pos - 1);
-                                    *cursor_pos -= 1;
+                            KeyCode::Char('r') => {
+                                match rotate_keys(conn, session.as_mut().ok_or("No active session")?) {
+                                    Ok(()) => *state = AppState::Menu { user_id: *user_id },
+                                    Err(err) => *error_message = Some(format!("Failed to rotate keys: {}", err)),
                                 }
                             }
-                            KeyCode::Left => {
-                                if *cursor_pos > 0 {
-                                    *cursor_pos -= 1;
+                            KeyCode::Char(c) if c.is_ascii_digit() => input_buffer.push(c),
+                            KeyCode::Backspace => { input_buffer.pop(); }
+                            KeyCode::Enter => {
+                                match input_buffer.parse::<i64>() {
+                                    Ok(days) => match set_rotation_interval(conn, *user_id, days) {
+                                        Ok(()) => *state = AppState::Menu { user_id: *user_id },
+                                        Err(err) => *error_message = Some(format!("Failed to save: {}", err)),
+                                    },
+                                    Err(_) => *error_message = Some("Enter a number of days".to_string()),
                                 }
                             }
-                            KeyCode::Right => {
-                                if *cursor_pos < input_buffer.len() {
-                                    *cursor_pos += 1;
-                                }
+                            KeyCode::Esc => {
+                                *state = AppState::Menu { user_id: *user_id };
                             }
-                            KeyCode::Enter => {
-                                match *step {
-                                    0 => {
-                                        let mut stmt = conn.prepare("SELECT 1 FROM users WHERE username = ?1")?;
-                                        let exists = stmt.exists([input_buffer.as_str()])?;
+                            _ => {}
+                        }
+                    }
 
-                                        if !exists {
-                                            *error_message = Some("User doesn't exist. Please Register.".to_string());
-                                            *error_time = Some(std::time::Instant::now());
-                                            input_buffer.clear();
-                                            *cursor_pos = 0;
-                                        } else {
-                                            *username = input_buffer.clone();
-                                            input_buffer.clear();
-                                            *cursor_pos = 0;
-                                            *step = 1;
-                                        }
+                    AppState::TwoFactor { user_id, enrollment, input_buffer, error_message } => {
+                        match (code, enrollment) {
+                            (KeyCode::Char(c), _) if c.is_ascii_digit() => input_buffer.push(c),
+                            (KeyCode::Backspace, _) => { input_buffer.pop(); }
+                            (KeyCode::Enter, Some((secret, _))) => {
+                                let session = session.as_ref().ok_or("No active session")?;
+                                match enable_totp(conn, session, secret, input_buffer) {
+                                    // Codes to fall back on if the authenticator gets lost
+                                    Ok(()) => {
+                                        *state = match generate_recovery_codes(conn, session) {
+                                            Ok(codes) => AppState::RecoveryCodes { user_id: *user_id, remaining: codes.len() as i64, codes, error_message: None },
+                                            // Generating again from the recovery codes screen may still work
+                                            Err(err) => AppState::RecoveryCodes {
+                                                user_id: *user_id,
+                                                codes: Vec::new(),
+                                                remaining: 0,
+                                                error_message: Some(format!("Two-factor login is on, but no recovery codes were generated: {}", err)),
+                                            },
+                                        };
                                     }
-                                    1 => {
-                                        *password = input_buffer.clone();
+                                    Err(password_manager_lib::Error::Authentication) => {
+                                        *error_message = Some("Code does not match, check the clock of your device".to_string());
                                         input_buffer.clear();
-                                        if let Some(user_id) = login_user(conn, username, password) {
-                                            *cursor_pos = 0;
-                                            *state = AppState::Menu { user_id };
-                                        } else {
-                                            *error_message = Some("Invalid password. Please try again.".to_string());
-                                            *error_time = Some(std::time::Instant::now());
-                                            *cursor_pos = 0;
-                                        }
                                     }
-                                    _ => {}
+                                    Err(err) => *error_message = Some(err.to_string()),
                                 }
                             }
-                            KeyCode::Esc => {
-                                input_buffer.clear();
+                            // Someone at an unlocked terminal should not be able to drop the second factor
+                            (KeyCode::Enter, None) => {
+                                match disable_totp(conn, session.as_ref().ok_or("No active session")?, input_buffer) {
+                                    Ok(()) => *state = AppState::Menu { user_id: *user_id },
+                                    Err(password_manager_lib::Error::Authentication) => {
+                                        *error_message = Some("Code does not match or was already used".to_string());
+                                        input_buffer.clear();
+                                    }
+                                    Err(err) => *error_message = Some(err.to_string()),
+                                }
+                            }
+                            // Leaving a required enrollment logs out instead of opening the vault
+                            (KeyCode::Esc, _) if totp_reenrollment_required(conn, *user_id)? => {
+                                session = None;
+                                key_file = None;
                                 *state = AppState::Start;
                             }
+                            (KeyCode::Esc, _) => {
+                                *state = AppState::Menu { user_id: *user_id };
+                            }
                             _ => {}
                         }
                     }
 
-                    AppState::Menu {user_id} => {
-                        match code {
-                            KeyCode::Down => {
-                                let new_index = (selected + 1).min(MENU_ITEMS.len() - 1);
-                                list_state.select(Some(new_index));
-                            }
-                            KeyCode::Up => {
-                                let new_index = selected.saturating_sub(1);
-                                list_state.select(Some(new_index));
-                            }
-                            KeyCode::Enter => match selected {
-                                0 => {
-                                    *state = AppState::CreateAccount {
-                                        user_id: user_id.clone(),
-                                        step: 0,
-                                        account: String::new(),
-                                        username: String::new(),
-                                        password: String::new(),
-                                        input_buffer: String::new(),
-                                        cursor_pos: 0,
-                                    };
-                                }
-                                1 => { *state = AppState::SearchVault {
-                                    user_id: {user_id.clone()},
-                                    input_buffer: String::new(),}; }
-                                2 => {
-                                    let mut vaults: Vec<(String, String, String)> = get_passwords(conn, user_id)?
-                                        .into_iter()
-                                        .map(|(acc, user, enc)| (acc, user, base64::encode(enc)))
-                                        .collect();
-
-                                    let show_headers = !vaults.is_empty();
-
-                                    if vaults.is_empty() {
-                                        vaults.push((
-                                            "No vaults created yet.".to_string(),
-                                            "".to_string(),
-                                            "".to_string()
-                                        ));
-                                    }
-
-                                    vaults.sort_by(|a, b| {
-                                        let site_cmp = a.0.to_lowercase().cmp(&b.0.to_lowercase());
-                                        if site_cmp == std::cmp::Ordering::Equal {
-                                            a.1.to_lowercase().cmp(&b.1.to_lowercase())
-                                        } else {
-                                            site_cmp
-                                        }
-                                    });
-
-                                    *state = AppState::ShowAllVaults {
-                                        user_id: user_id.clone(),
-                                        entries: vaults,
-                                        scroll: 0,
-                                        selected: 0,
-                                        show_password: false,
-                                        show_headers,
-                                    };
-                                }
-                                3 => *state = AppState::Start,
-                                _ => {}
-                            },
-                            KeyCode::Char('q') => return Ok(()),
-                            _ => {}
+                    AppState::RecoveryKey { user_id, .. } => {
+                        if let KeyCode::Enter = code {
+                            *state = AppState::Menu { user_id: *user_id };
                         }
                     }
 
-                    AppState::ShowAllVaults {user_id, scroll, selected, show_password, entries, show_headers} => {
+                    AppState::ForgotPassword {step, username, recovery_words, password, password2, input_buffer, cursor_pos, error_message, error_time} => {
                         match code {
-                            KeyCode::Esc => {
-                                *state = AppState::Menu {user_id: user_id.clone()};
-                            }
-                            KeyCode::Down => {
-                                if *selected < entries.len().saturating_sub(1) {
-                                    *selected += 1;
-
-                                    let mut line_index = 0;
-                                    let mut last_letter: Option<char> = None;
-
-                                    for (i, (acc, _, _)) in entries.iter().enumerate() {
-                                        let first_letter = acc.chars().next().unwrap_or('?').to_ascii_uppercase();
-                                        if Some(first_letter) != last_letter {
-                                            line_index += 1;
-                                            last_letter = Some(first_letter);
-                                        }
-                                        if i == *selected {
-                                            break;
-                                        }
-                                        line_index += 1;
+                            KeyCode::Char(c)
+                                if *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.insert(*cursor_pos, c);
+                                    *cursor_pos += 1;
+                                }
+                            KeyCode::Backspace
+                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.remove(*cursor_pos - 1);
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Left
+                                if *cursor_pos > 0 => {
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Right
+                                if *cursor_pos < input_buffer.len() => {
+                                    *cursor_pos += 1;
+                                }
+                            KeyCode::Enter => {
+                                match *step {
+                                    0 => {
+                                        *username = input_buffer.to_string();
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        *step = 1;
                                     }
-
-                                    let terminal_height = terminal.size()?.height.saturating_sub(4) as usize;
-                                    if line_index >= *scroll as usize + terminal_height {
-                                        *scroll = (line_index + 1 - terminal_height) as u16;
+                                    1 => {
+                                        *recovery_words = input_buffer.clone();
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        *step = 2;
+                                    }
+                                    2 => {
+                                        *password = input_buffer.clone();
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        *step = 3;
+                                    }
+                                    3 => {
+                                        *password2 = input_buffer.clone();
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        if password != password2 {
+                                            *error_message = Some("Passwords do not match".to_string());
+                                            *error_time = Some(std::time::Instant::now());
+                                            *step = 2;
+                                        } else {
+                                            match recover_account(conn, username, recovery_words, password) {
+                                                Ok(new_session) => {
+                                                    *state = logged_in_state(conn, new_session.user_id)?;
+                                                    key_file = None;
+                                                    session = Some(new_session);
+                                                }
+                                                Err(err) => {
+                                                    *error_message = Some(match err {
+                                                        password_manager_lib::Error::Authentication => "Nickname or recovery key is incorrect".to_string(),
+                                                        err => err.to_string(),
+                                                    });
+                                                    *error_time = Some(std::time::Instant::now());
+                                                    *step = 0;
+                                                }
+                                            }
+                                        }
                                     }
+                                    _ => {}
                                 }
                             }
-                            KeyCode::Up => {
-                                if *selected > 0 {
-                                    *selected -= 1;
-
-                                    let mut line_index = 0;
-                                    let mut last_letter: Option<char> = None;
+                            KeyCode::Esc => {
+                                input_buffer.clear();
+                                *state = AppState::Start;
+                            }
+                            _ => {}
+                        }
+                    }
 
-                                    for (i, (acc, _, _)) in entries.iter().enumerate() {
-                                        let first_letter = acc.chars().next().unwrap_or('?').to_ascii_uppercase();
-                                        if Some(first_letter) != last_letter {
-                                            line_index += 1;
-                                            last_letter = Some(first_letter);
-                                        }
-                                        if i == *selected {
-                                            break;
-                                        }
-                                        line_index += 1;
+                    AppState::KeyFile {user_id, step, password, input_buffer, cursor_pos, error_message, error_time} => {
+                        match code {
+                            KeyCode::Char(c)
+                                if *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.insert(*cursor_pos, c);
+                                    *cursor_pos += 1;
+                                }
+                            KeyCode::Backspace
+                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.remove(*cursor_pos - 1);
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Left
+                                if *cursor_pos > 0 => {
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Right
+                                if *cursor_pos < input_buffer.len() => {
+                                    *cursor_pos += 1;
+                                }
+                            KeyCode::Enter => {
+                                match *step {
+                                    0 => {
+                                        *password = input_buffer.clone();
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        *step = 1;
                                     }
+                                    1 => {
+                                        let path = std::path::Path::new(input_buffer.as_str());
+                                        // An empty path removes the key file, a missing file is created with random contents
+                                        let new_key_file = if input_buffer.is_empty() {
+                                            Ok(None)
+                                        } else if path.exists() {
+                                            std::fs::read(path).map(|contents| Some(SecretKey::new(contents))).map_err(password_manager_lib::Error::from)
+                                        } else {
+                                            let contents = generate_key();
+                                            keystore::write_private(path, &contents).map(|()| Some(contents))
+                                        };
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
 
-                                    if line_index < *scroll as usize {
-                                        *scroll = line_index as u16;
+                                        match new_key_file {
+                                            Ok(new_key_file) => {
+                                                match set_key_file(conn, *user_id, password, key_file.as_deref(), new_key_file.as_deref()) {
+                                                    Ok(new_session) => {
+                                                        *state = AppState::Menu { user_id: *user_id };
+                                                        key_file = new_key_file;
+                                                        session = Some(new_session);
+                                                    }
+                                                    Err(err) => {
+                                                        *error_message = Some(match err {
+                                                            password_manager_lib::Error::Authentication => "Master password is incorrect".to_string(),
+                                                            err => err.to_string(),
+                                                        });
+                                                        *error_time = Some(std::time::Instant::now());
+                                                        *step = 0;
+                                                    }
+                                                }
+                                            }
+                                            Err(err) => {
+                                                *error_message = Some(format!("Cannot use key file: {}", err));
+                                                *error_time = Some(std::time::Instant::now());
+                                            }
+                                        }
                                     }
+                                    _ => {}
                                 }
                             }
-                            KeyCode::Enter => {
-                                let (acc, user, enc) = &entries[*selected];
-                                if acc == "Sorry, no results :(" || acc == "No vaults created yet." {
-                                    continue;
-                                }
-                                let decrypted = decrypt(&base64::decode(enc).unwrap_or_default(), key).unwrap_or("ERR".to_string());
-
-                                *state = AppState::ViewVaultDetail {
-                                    user_id: user_id.clone(),
-                                    account: acc.clone(),
-                                    username: user.clone(),
-                                    password: decrypted,
-                                    previous_entries: entries.clone(),
-                                    previous_scroll: *scroll,
-                                    previous_selected: *selected,
-                                    scroll: 0,
-                                    previous_show_headers: *show_headers,
-                                    email_emoji_pos: None,
-                                    pass_emoji_pos: None,
-                                    copy_message: None,
-                                    obscure_password: true,
-                                };
+                            KeyCode::Esc => {
+                                *state = AppState::Menu { user_id: *user_id };
                             }
                             _ => {}
                         }
                     }
 
-                    AppState::ViewVaultDetail {
-                        user_id,
-                        account,
-                        username,
-                        password,
-                        previous_entries,
-                        previous_scroll,
-                        previous_selected,
-                        scroll,
-                        previous_show_headers,
-                        obscure_password,
-                        ..
-                    } => {
+                    AppState::RecoveryCodes { user_id, codes, remaining, error_message } => {
                         match code {
+                            KeyCode::Char('g') => {
+                                match generate_recovery_codes(conn, session.as_ref().ok_or("No active session")?) {
+                                    Ok(new_codes) => {
+                                        *codes = new_codes;
+                                        *remaining = codes.len() as i64;
+                                        *error_message = None;
+                                    }
+                                    Err(err) => *error_message = Some(err.to_string()),
+                                }
+                            }
                             KeyCode::Esc => {
-                                *state = AppState::ShowAllVaults {
-                                    user_id: user_id.clone(),
-                                    entries: previous_entries.clone(),
-                                    scroll: *previous_scroll,
-                                    selected: *previous_selected,
-                                    show_password: false,
-                                    show_headers: *previous_show_headers,
-                                };
+                                *state = AppState::Menu { user_id: *user_id };
                             }
-                            KeyCode::Down => {
-                                let content_lines: u16 = 3 * 3;
-                                let visible_lines = terminal.size()?.height.saturating_sub(4);
-
-                                let max_scroll = content_lines.saturating_sub(visible_lines);
+                            _ => {}
+                        }
+                    }
 
-                                if *scroll < max_scroll {
-                                    *scroll += 1;
+                    AppState::Tampered { user_id, error_message, .. } => {
+                        match code {
+                            KeyCode::Enter => {
+                                let current = session.as_ref().ok_or("No active session")?;
+                                match reseal_manifest(conn, current, seen_manifest_counter(MANIFEST_COUNTERS_FILE, *user_id)) {
+                                    Ok(counter) => match save_manifest_counter(MANIFEST_COUNTERS_FILE, *user_id, counter) {
+                                        Ok(()) => {
+                                            verified = Some((*user_id, counter));
+                                            *state = logged_in_state(conn, *user_id)?;
+                                        }
+                                        Err(err) => *error_message = Some(format!("Cannot save the manifest counter: {}", err)),
+                                    },
+                                    Err(err) => *error_message = Some(err.to_string()),
                                 }
                             }
-                            KeyCode::Up => {
-                                if *scroll > 0 {
-                                    *scroll -= 1;
-                                }
+                            KeyCode::Esc => {
+                                session = None;
+                                key_file = None;
+                                *state = AppState::Start;
                             }
-                            KeyCode::Char('d') => {
-                                delete_vault(conn, account, username, user_id)?;
-                                let mut new_entries = previous_entries.clone();
-                                new_entries.retain(|(a, u, _)| a != account || u != username);
+                            _ => {}
+                        }
+                    }
 
-                                if new_entries.is_empty() {
-                                    new_entries.push((
-                                        "Sorry, no results :(".to_string(),
-                                        "".to_string(),
-                                        "".to_string(),
-                                    ));
-                                }
-                                
-                                *state = AppState::ShowAllVaults {
-                                    user_id: user_id.clone(),
-                                    entries: new_entries,
-                                    scroll: *previous_scroll,
-                                    selected: 0,
-                                    show_password: false,
-                                    show_headers: *previous_show_headers,
-                                };
+                    AppState::UnreadableEntries { user_id, .. } => {
+                        match code {
+                            KeyCode::Enter => {
+                                *state = logged_in_state(conn, *user_id)?;
                             }
-                            KeyCode::Char('e') => {
-                                *state = AppState::EditVault {
-                                    user_id: user_id.clone(),
-                                    step: 0,
-                                    account: account.clone(),
-                                    username: username.clone(),
-                                    password: password.clone(),
-                                    input_buffer: account.clone(),
-                                    old_account: account.clone(),
-                                    old_username: username.clone(),
-                                    temp_account: account.clone(),
-                                    temp_username: username.clone(),
-                                    temp_password: password.clone(),
-                                    previous_entries: previous_entries.clone(),
-                                    previous_scroll: *previous_scroll,
-                                    previous_selected: *previous_selected,
-                                    previous_show_headers: *previous_show_headers,
-                                    started_editing: false,
-                                    cursor_pos: account.len(),
-                                };
+                            KeyCode::Esc => {
+                                session = None;
+                                key_file = None;
+                                *state = AppState::Start;
                             }
-                            KeyCode::Char('u') => {
-                                if let Ok(mut cb) = Clipboard::new() {
-                                    cb.set_text(username.clone()).ok();
-                                }
+                            _ => {}
+                        }
+                    }
 
-                                *state = AppState::ViewVaultDetail {
-                                    user_id: user_id.clone(),
-                                    account: account.clone(),
-                                    username: username.clone(),
-                                    password: password.clone(),
-                                    previous_entries: previous_entries.clone(),
-                                    previous_scroll: *previous_scroll,
-                                    previous_selected: *previous_selected,
-                                    scroll: *scroll,
-                                    previous_show_headers: *previous_show_headers,
-                                    email_emoji_pos: None,
-                                    pass_emoji_pos: None,
-                                    copy_message: Some(("Email/Username copied!".to_string(), std::time::Instant::now())),
-                                    obscure_password: *obscure_password,
+                    AppState::RememberDevice { user_id, username, remembered, error_message } => {
+                        match code {
+                            KeyCode::Enter => {
+                                let result = if *remembered {
+                                    forget_device(key_store, username)
+                                } else {
+                                    remember_device(key_store, username, session.as_ref().ok_or("No active session")?)
                                 };
-                            }
-                            KeyCode::Char('p') => {
-                                if let Ok(mut cb) = Clipboard::new() {
-                                    cb.set_text(password.clone()).ok();
+                                match result {
+                                    Ok(()) => {
+                                        *remembered = !*remembered;
+                                        *error_message = None;
+                                    }
+                                    Err(err) => *error_message = Some(err.to_string()),
                                 }
-
-                                *state = AppState::ViewVaultDetail {
-                                    user_id: user_id.clone(),
-                                    account: account.clone(),
-                                    username: username.clone(),
-                                    password: password.clone(),
-                                    previous_entries: previous_entries.clone(),
-                                    previous_scroll: *previous_scroll,
-                                    previous_selected: *previous_selected,
-                                    scroll: *scroll,
-                                    previous_show_headers: *previous_show_headers,
-                                    email_emoji_pos: None,
-                                    pass_emoji_pos: None,
-                                    copy_message: Some(("Password copied!".to_string(), std::time::Instant::now())),
-                                    obscure_password: *obscure_password,
-                                };
                             }
-                            KeyCode::Char('s') => {
-                                *state = AppState::ViewVaultDetail {
-                                    user_id: user_id.clone(),
-                                    account: account.clone(),
-                                    username: username.clone(),
-                                    password: password.clone(),
-                                    previous_entries: previous_entries.clone(),
-                                    previous_scroll: *previous_scroll,
-                                    previous_selected: *previous_selected,
-                                    scroll: *scroll,
-                                    previous_show_headers: *previous_show_headers,
-                                    email_emoji_pos: None,
-                                    pass_emoji_pos: None,
-                                    copy_message: None,
-                                    obscure_password: !*obscure_password,
-                                };
+                            KeyCode::Esc => {
+                                *state = AppState::Menu { user_id: *user_id };
                             }
                             _ => {}
                         }
                     }
 
-                    AppState::SearchVault {user_id, input_buffer } => {
+                    AppState::DeleteAccount { user_id, username, step, input_buffer, cursor_pos, error_message } => {
                         match code {
-                            KeyCode::Char(c) => input_buffer.push(c),
-                            KeyCode::Backspace => { input_buffer.pop(); }
-                            KeyCode::Enter => {
-                                if input_buffer.trim().is_empty() {
-                                    continue;
+                            KeyCode::Char(c)
+                                if *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.insert(*cursor_pos, c);
+                                    *cursor_pos += 1;
                                 }
-                                
-                                let filtered: Vec<_> = get_passwords(conn, user_id)?
-                                    .into_iter()
-                                    .filter(|(site, _, _)| site.to_lowercase().contains(&input_buffer.to_lowercase()))
-                                    .map(|(acc, user, enc)| (acc, user, base64::encode(enc)))
-                                    .collect();
-
-                                let mut entries = if filtered.is_empty() {
-                                    vec![("Sorry, no results :(".to_string(), "".to_string(), "".to_string())]
-                                } else {
-                                    filtered
-                                };
-
-                                entries.sort_by(|a, b| {
-                                    let site_cmp = a.0.to_lowercase().cmp(&b.0.to_lowercase());
-                                    if site_cmp == std::cmp::Ordering::Equal {
-                                        a.1.to_lowercase().cmp(&b.1.to_lowercase())
-                                    } else {
-                                        site_cmp
+                            KeyCode::Backspace
+                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() => {
+                                    input_buffer.remove(*cursor_pos - 1);
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Left
+                                if *cursor_pos > 0 => {
+                                    *cursor_pos -= 1;
+                                }
+                            KeyCode::Right
+                                if *cursor_pos < input_buffer.len() => {
+                                    *cursor_pos += 1;
+                                }
+                            KeyCode::Enter => {
+                                match *step {
+                                    0 => {
+                                        if input_buffer.as_str() == username.as_str() {
+                                            *error_message = None;
+                                            *step = 1;
+                                        } else {
+                                            *error_message = Some("Username does not match".to_string());
+                                        }
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
                                     }
-                                });
-
-                                *state = AppState::ShowAllVaults {
-                                    user_id: user_id.clone(),
-                                    entries,
-                                    scroll: 0,
-                                    selected: 0,
-                                    show_password: false,
-                                    show_headers: false,
-                                };
+                                    _ => {
+                                        let result = delete_user_with_key_file(conn, *user_id, input_buffer, key_file.as_deref());
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        match result {
+                                            Ok(()) => {
+                                                // The stored key could no longer unlock anything
+                                                forget_device(key_store, username).ok();
+                                                session = None;
+                                                key_file = None;
+                                                *state = AppState::Start;
+                                            }
+                                            Err(password_manager_lib::Error::Authentication) => {
+                                                *error_message = Some("Master password is incorrect".to_string());
+                                            }
+                                            Err(err) => *error_message = Some(err.to_string()),
+                                        }
+                                    }
+                                }
                             }
                             KeyCode::Esc => {
-                                *state = AppState::Menu{user_id: user_id.clone(),};
+                                *state = AppState::Menu { user_id: *user_id };
                             }
                             _ => {}
                         }
@@ -1242,62 +2623,77 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                         username,
                         password,
                         cursor_pos,
+                        error_message,
                     } => {
                         match code {
                             KeyCode::Char(c) if *step == 2 && (c == '#') => {
-                                let generated = generate_strong_password(16);
+                                let generated = SecretString::from(generate_strong_password(16));
                                 *password = generated.clone();
                                 *input_buffer = generated;
                                 *cursor_pos = input_buffer.len();
                             }
-                            KeyCode::Char(c) => {
-                                if *cursor_pos <= input_buffer.len() {
+                            KeyCode::Char(c)
+                                if *cursor_pos <= input_buffer.len() => {
                                     input_buffer.insert(*cursor_pos, c);
                                     *cursor_pos += 1;
                                 }
-                            }
-                            KeyCode::Backspace => {
-                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() {
+                            KeyCode::Backspace
+                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() => {
                                     input_buffer.remove(*cursor_pos - 1);
                                     *cursor_pos -= 1;
                                 }
-                            }
-                            KeyCode::Left => {
-                                if *cursor_pos > 0 {
+                            KeyCode::Left
+                                if *cursor_pos > 0 => {
                                     *cursor_pos -= 1;
                                 }
-                            }
-                            KeyCode::Right => {
-                                if *cursor_pos < input_buffer.len() {
+                            KeyCode::Right
+                                if *cursor_pos < input_buffer.len() => {
                                     *cursor_pos += 1;
                                 }
-                            }
                             KeyCode::Enter => {
                                 match *step {
                                     0 => {
-                                        *account = input_buffer.clone();
+                                        *account = input_buffer.to_string();
                                         input_buffer.clear();
                                         *cursor_pos = 0;
                                         *step = 1;
                                     }
                                     1 => {
-                                        *username = input_buffer.clone();
+                                        *username = input_buffer.to_string();
                                         input_buffer.clear();
                                         *cursor_pos = 0;
                                         *step = 2;
                                     }
                                     2 => {
                                         *password = input_buffer.clone();
+                                        input_buffer.clear();
+                                        *cursor_pos = 0;
+                                        *step = 3;
+                                    }
+                                    3 => {
+                                        let otp = if input_buffer.trim().is_empty() {
+                                            None
+                                        } else {
+                                            match OtpAuth::parse(input_buffer) {
+                                                Ok(otp) => Some(otp),
+                                                Err(err) => {
+                                                    *error_message = Some(err.to_string());
+                                                    continue;
+                                                }
+                                            }
+                                        };
 
-                                        let encrypted = encrypt(password, key);
-                                        insert_password(conn, account, username, &encrypted, user_id)?;
-                                        *state = AppState::Menu{user_id: user_id.clone(),};
+                                        let session = session.as_ref().ok_or("No active session")?;
+                                        match insert_entry(conn, session, account, username, password, otp.as_ref()) {
+                                            Ok(_) => *state = AppState::Menu{user_id: *user_id,},
+                                            Err(err) => *error_message = Some(format!("Failed to save: {}", err)),
+                                        }
                                     }
                                     _ => {}
                                 }
                             }
                             KeyCode::Esc => {
-                                *state = AppState::Menu{user_id: user_id.clone(),};
+                                *state = AppState::Menu{user_id: *user_id,};
                             }
                             _ => {}
                         }
@@ -1306,12 +2702,8 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                     AppState::EditVault {
                         user_id,
                         step,
-                        account,
-                        username,
-                        password,
+                        entry,
                         input_buffer,
-                        old_account,
-                        old_username,
                         temp_account,
                         temp_username,
                         temp_password,
@@ -1319,95 +2711,106 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                         previous_scroll,
                         previous_selected,
                         previous_show_headers,
-                        started_editing,
                         cursor_pos,
+                        error_message,
                     } => {
                         match code {
                             KeyCode::Char('#') if *step == 2 => {
-                                let generated = generate_strong_password(16);
-                                *input_buffer = generated.clone();
+                                let generated = SecretString::from(generate_strong_password(16));
                                 *cursor_pos = generated.len();
+                                *input_buffer = generated;
                             }
-                            KeyCode::Char(c) => {
-                                if *cursor_pos <= input_buffer.len() {
+                            KeyCode::Char(c)
+                                if *cursor_pos <= input_buffer.len() => {
                                     input_buffer.insert(*cursor_pos, c);
                                     *cursor_pos += 1;
                                 }
-                            }
-                            KeyCode::Backspace => {
-                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() {
+                            KeyCode::Backspace
+                                if *cursor_pos > 0 && *cursor_pos <= input_buffer.len() => {
                                     input_buffer.remove(*cursor_pos - 1);
                                     *cursor_pos -= 1;
                                 }
-                            }
-                            KeyCode::Left => {
-                                if *cursor_pos > 0 {
+                            KeyCode::Left
+                                if *cursor_pos > 0 => {
                                     *cursor_pos -= 1;
                                 }
-                            }
-                            KeyCode::Right => {
-                                if *cursor_pos < input_buffer.len() {
+                            KeyCode::Right
+                                if *cursor_pos < input_buffer.len() => {
                                     *cursor_pos += 1;
                                 }
-                            }
                             KeyCode::Enter => {
                                 match *step {
                                     0 => {
-                                        *temp_account = input_buffer.clone();
+                                        *temp_account = input_buffer.to_string();
                                         *step = 1;
-                                        *input_buffer = username.clone();
+                                        *input_buffer = entry.username.as_str().into();
                                         *cursor_pos = input_buffer.len();
                                     }
                                     1 => {
-                                        *temp_username = input_buffer.clone();
+                                        *temp_username = input_buffer.to_string();
                                         *step = 2;
-                                        *input_buffer = password.clone();
+                                        *input_buffer = entry.password.clone();
                                         *cursor_pos = input_buffer.len();
                                     }
                                     2 => {
-                                        *cursor_pos = input_buffer.len();
                                         *temp_password = input_buffer.clone();
+                                        *step = 3;
+                                        *input_buffer = entry.otp.as_ref().map(|otp| otp.to_uri()).unwrap_or_default().into();
+                                        *cursor_pos = input_buffer.len();
+                                    }
+                                    3 => {
+                                        *cursor_pos = input_buffer.len();
+                                        let otp = if input_buffer.trim().is_empty() {
+                                            None
+                                        } else {
+                                            match OtpAuth::parse(input_buffer) {
+                                                Ok(otp) => Some(otp),
+                                                Err(err) => {
+                                                    *error_message = Some(err.to_string());
+                                                    continue;
+                                                }
+                                            }
+                                        };
 
-                                        *account = temp_account.clone();
-                                        *username = temp_username.clone();
-                                        *password = temp_password.clone();
-
-                                        let encrypted = encrypt(password, key);
-                                        update_vault(conn, old_account, old_username, account, username, encrypted.as_slice(), user_id)?;
-
-                                        let mut updated_entries: Vec<(String, String, String)> = get_passwords(conn, user_id)?
-                                            .into_iter()
-                                            .map(|(a, u, e)| (a, u, base64::encode(e)))
-                                            .collect();
+                                        let edited = VaultEntry {
+                                            account: temp_account.clone(),
+                                            username: temp_username.clone(),
+                                            password: temp_password.clone(),
+                                            otp,
+                                            ..entry.clone()
+                                        };
 
-                                        updated_entries.sort_by(|a, b| {
-                                            let site_cmp = a.0.to_lowercase().cmp(&b.0.to_lowercase());
-                                            if site_cmp == std::cmp::Ordering::Equal {
-                                                a.1.to_lowercase().cmp(&b.1.to_lowercase())
-                                            } else {
-                                                site_cmp
+                                        let session = session.as_ref().ok_or("No active session")?;
+                                        let updated = match update_entry(conn, session, &edited) {
+                                            Ok(updated) => updated,
+                                            Err(err) => {
+                                                *error_message = Some(format!("Failed to save: {}", err));
+                                                continue;
                                             }
-                                        });
+                                        };
+
+                                        let mut updated_entries = previous_entries.clone();
+                                        for listed in updated_entries.iter_mut().filter(|listed| listed.id == updated.id) {
+                                            *listed = updated.clone();
+                                        }
+                                        sort_entries(&mut updated_entries);
 
                                         let selected_index = updated_entries
                                             .iter()
-                                            .position(|(a, u, _)| a == account && u == username)
+                                            .position(|listed| listed.id == updated.id)
                                             .unwrap_or(0);
 
                                         *state = AppState::ViewVaultDetail {
-                                            user_id: user_id.clone(),
-                                            account: account.clone(),
-                                            username: username.clone(),
-                                            password: password.clone(),
+                                            user_id: *user_id,
+                                            entry: updated,
                                             previous_entries: updated_entries,
                                             previous_scroll: 0,
                                             previous_selected: selected_index,
                                             scroll: 0,
-                                            previous_show_headers: true,
-                                            email_emoji_pos: None,
-                                            pass_emoji_pos: None,
+                                            previous_show_headers: *previous_show_headers,
                                             copy_message: None,
                                             obscure_password: true,
+                                            error_message: None,
                                         };
                                     }
                                     _ => {}
@@ -1415,19 +2818,16 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
                             }
                             KeyCode::Esc => {
                                 *state = AppState::ViewVaultDetail {
-                                    user_id: user_id.clone(),
-                                    account: old_account.clone(),
-                                    username: old_username.clone(),
-                                    password: password.clone(),
+                                    user_id: *user_id,
+                                    entry: entry.clone(),
                                     previous_entries: previous_entries.clone(),
                                     previous_scroll: *previous_scroll,
                                     previous_selected: *previous_selected,
                                     scroll: 0,
                                     previous_show_headers: *previous_show_headers,
-                                    email_emoji_pos: None,
-                                    pass_emoji_pos: None,
                                     copy_message: None,
                                     obscure_password: true,
+                                    error_message: None,
                                 };
                             }
                             _ => {}
@@ -1441,6 +2841,4 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, key: &[u8
 
 
     }
-
-    Ok(())
 }
@@ -34,4 +34,33 @@ pub fn generate_strong_password(_len: usize) -> String {
     }
 
     password
+}
+
+/// Highest manifest counter this device has seen for `user_id`, kept outside the database
+/// so that a rolled back copy of it gives itself away.
+pub fn seen_manifest_counter(path: &str, user_id: i64) -> Option<i64> {
+    read_manifest_counters(path).remove(&user_id)
+}
+
+pub fn save_manifest_counter(path: &str, user_id: i64, counter: i64) -> std::io::Result<()> {
+    let mut counters = read_manifest_counters(path);
+    counters.insert(user_id, counter);
+
+    let contents: String = counters
+        .iter()
+        .map(|(user_id, counter)| format!("{} {}\n", user_id, counter))
+        .collect();
+    std::fs::write(path, contents)
+}
+
+// One "<user id> <counter>" line per user; a missing or unreadable file knows no counters
+fn read_manifest_counters(path: &str) -> std::collections::BTreeMap<i64, i64> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (user_id, counter) = line.split_once(' ')?;
+            Some((user_id.parse().ok()?, counter.parse().ok()?))
+        })
+        .collect()
 }
\ No newline at end of file
@@ -1,35 +1,242 @@
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use argon2::password_hash::{SaltString, rand_core::OsRng};
+use std::fmt::Write;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::{SaltString, rand_core::{OsRng, RngCore}};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use crate::secret::{SecretKey, SecretString};
+use crate::{Error, Result};
 
+/// Unlock latency new accounts are calibrated for.
+pub const DEFAULT_UNLOCK_TIME: Duration = Duration::from_millis(500);
 
-pub fn hash_password(password: &str) -> (String, Vec<u8>) {
+// Recovery codes avoid 0/o and 1/l so they survive being copied by hand
+const RECOVERY_CODE_ALPHABET: &[u8; 32] = b"23456789abcdefghijkmnpqrstuvwxyz";
+const RECOVERY_CODE_GROUPS: usize = 3;
+const RECOVERY_CODE_GROUP_LEN: usize = 4;
+
+// Bytes of a recovery key, a data key like any other
+const RECOVERY_KEY_LEN: usize = 32;
+
+// Upper bounds so a very fast machine does not produce parameters a slower one cannot open
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 10;
+
+/// Argon2 settings used both for the password hash and for deriving the key-wrapping key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: Algorithm,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// Settings of `Argon2::default()`, which every account created before per-user parameters used.
+    pub const LEGACY: KdfParams = KdfParams {
+        algorithm: Algorithm::Argon2id,
+        m_cost: Params::DEFAULT_M_COST,
+        t_cost: Params::DEFAULT_T_COST,
+        p_cost: Params::DEFAULT_P_COST,
+    };
+
+    pub fn is_weaker_than(&self, other: &KdfParams) -> bool {
+        self.algorithm != other.algorithm
+            || self.m_cost < other.m_cost
+            || self.t_cost < other.t_cost
+            || self.p_cost < other.p_cost
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))?;
+        Ok(Argon2::new(self.algorithm, Version::V0x13, params))
+    }
+}
+
+impl Default for KdfParams {
+    /// Current policy for new and upgraded accounts.
+    fn default() -> Self {
+        KdfParams {
+            algorithm: Algorithm::Argon2id,
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+pub fn hash_password(password: &str, params: &KdfParams) -> Result<(String, Vec<u8>)> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
 
-    let hash = argon2
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap()
+    let hash = params
+        .argon2()?
+        .hash_password(password.as_bytes(), &salt)?
         .to_string();
 
-    (hash, salt.as_str().as_bytes().to_vec())
+    Ok((hash, salt.as_str().as_bytes().to_vec()))
+}
+
+/// `Ok(false)` means the password is wrong; errors mean the stored hash is unusable.
+pub fn verify_password(hash: &str, password: &str) -> Result<bool> {
+    let parsed_hash = PasswordHash::new(hash).map_err(|err| Error::Encoding(format!("password hash: {}", err)))?;
+
+    // Parameters are read from the hash itself
+    match Argon2::default().verify_password(password.as_bytes(), &parsed_hash) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn generate_salt() -> String {
+    SaltString::generate(&mut OsRng).as_str().to_string()
+}
+
+/// Random single-use code such as `7kx2-m9qp-a4tw`, 60 bits of entropy.
+pub fn generate_recovery_code() -> SecretString {
+    let mut bytes = [0u8; RECOVERY_CODE_GROUPS * RECOVERY_CODE_GROUP_LEN];
+    OsRng.fill_bytes(&mut bytes);
+
+    let mut code = SecretString::with_capacity(bytes.len() + RECOVERY_CODE_GROUPS);
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 && i % RECOVERY_CODE_GROUP_LEN == 0 {
+            code.push('-');
+        }
+        // 256 is a multiple of the alphabet size, so every character is equally likely
+        code.push(RECOVERY_CODE_ALPHABET[*byte as usize % RECOVERY_CODE_ALPHABET.len()] as char);
+    }
+    bytes.fill(0);
+
+    code
+}
+
+/// Form a recovery code is hashed and compared in, ignoring case, spaces and dashes.
+pub fn normalize_recovery_code(code: &str) -> SecretString {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect::<String>()
+        .into()
 }
 
-pub fn verify_password(hash: &str, password: &str) -> bool {
-    let parsed_hash = PasswordHash::new(hash).unwrap();
+/// Recovery key as a BIP 39 word list, which is easier to write down than hex.
+pub fn recovery_words(key: &[u8]) -> Result<SecretString> {
+    let mnemonic = bip39::Mnemonic::from_entropy(key).map_err(|err| Error::Encoding(format!("recovery key: {}", err)))?;
+    Ok(mnemonic.to_string().into())
+}
+
+/// Recovery key written as words by `recovery_words`. The word list checksum catches most typos.
+///
+/// Only the 24 words of a 32-byte key are accepted; shorter valid phrases are not recovery keys.
+pub fn parse_recovery_words(words: &str) -> Result<SecretKey> {
+    let mnemonic = bip39::Mnemonic::parse(words.trim().to_lowercase()).map_err(|err| Error::Encoding(format!("recovery key: {}", err)))?;
+    let key = SecretKey::new(mnemonic.to_entropy());
+    if key.len() != RECOVERY_KEY_LEN {
+        return Err(Error::Encoding(format!("recovery key: {} words instead of 24", mnemonic.word_count())));
+    }
+    Ok(key)
+}
 
-    Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok()
+/// Master password mixed with the contents of a key file.
+///
+/// It is hashed and fed to `derive_key_from_password` in place of the password,
+/// so neither the password nor the key file unlocks the vault alone.
+pub fn composite_password(password: &str, key_file: &[u8]) -> SecretString {
+    let digest = Sha256::digest(key_file);
+
+    let mut composite = SecretString::with_capacity(password.len() + 1 + 2 * digest.len());
+    composite.push_str(password);
+    composite.push('\0');
+    for byte in digest {
+        write!(&mut *composite, "{:02x}", byte).expect("writing to a String cannot fail");
+    }
+
+    composite
 }
 
-pub fn derive_key_from_password(password: &str, salt_str: &str) -> Vec<u8> {
-    let salt = SaltString::new(salt_str).unwrap();
-    let argon2 = Argon2::default();
-    let hash = argon2
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap()
+pub fn derive_key_from_password(password: &str, salt_str: &str, params: &KdfParams) -> Result<SecretKey> {
+    let salt = SaltString::from_b64(salt_str).map_err(|err| Error::Encoding(format!("key salt: {}", err)))?;
+    let hash = params
+        .argon2()?
+        .hash_password(password.as_bytes(), &salt)?
         .hash
-        .unwrap();
+        .ok_or_else(|| Error::Kdf("no output".to_string()))?;
+
+    Ok(SecretKey::new(hash.as_bytes().to_vec()))
+}
+
+/// Hash of the stored columns of one vault entry, as the manifest lists it.
+pub fn entry_digest(columns: &[Option<&[u8]>]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for column in columns {
+        // Length prefixes keep a missing column apart from an empty one and bytes from moving between columns
+        match column {
+            Some(bytes) => {
+                hasher.update([1]);
+                hasher.update((bytes.len() as u64).to_le_bytes());
+                hasher.update(bytes);
+            }
+            None => hasher.update([0]),
+        }
+    }
+    hasher.finalize().into()
+}
+
+/// HMAC-SHA256 over a vault manifest: its owner, change counter and serialized entries.
+pub fn manifest_mac(key: &[u8], user_id: i64, counter: i64, entries: &[u8]) -> Vec<u8> {
+    manifest_hmac(key, user_id, counter, entries).finalize().into_bytes().to_vec()
+}
+
+/// Checks `mac` against the manifest in constant time.
+pub fn verify_manifest_mac(key: &[u8], user_id: i64, counter: i64, entries: &[u8], mac: &[u8]) -> bool {
+    manifest_hmac(key, user_id, counter, entries).verify_slice(mac).is_ok()
+}
+
+fn manifest_hmac(key: &[u8], user_id: i64, counter: i64, entries: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(b"vault manifest\0");
+    mac.update(&user_id.to_le_bytes());
+    mac.update(&counter.to_le_bytes());
+    mac.update(entries);
+    mac
+}
+
+/// Picks the most expensive parameters that still derive a key within `target` on this machine.
+///
+/// Memory cost is raised first, as it is what makes offline guessing on GPUs expensive,
+/// then the iteration count. The result is never weaker than `KdfParams::default()`.
+pub fn calibrate(target: Duration) -> KdfParams {
+    let salt = generate_salt();
+    let fits = |params: &KdfParams| {
+        let start = Instant::now();
+        derive_key_from_password("calibration", &salt, params).is_ok() && start.elapsed() <= target
+    };
+
+    let mut params = KdfParams::default();
+
+    while params.m_cost < MAX_M_COST {
+        let candidate = KdfParams { m_cost: params.m_cost * 2, ..params };
+        if !fits(&candidate) {
+            break;
+        }
+        params = candidate;
+    }
+
+    while params.t_cost < MAX_T_COST {
+        let candidate = KdfParams { t_cost: params.t_cost + 1, ..params };
+        if !fits(&candidate) {
+            break;
+        }
+        params = candidate;
+    }
+
+    params
+}
+
+/// `calibrate(DEFAULT_UNLOCK_TIME)`, measured once per process.
+pub fn calibrated_params() -> KdfParams {
+    static CALIBRATED: OnceLock<KdfParams> = OnceLock::new();
 
-    hash.as_bytes().to_vec()
+    *CALIBRATED.get_or_init(|| calibrate(DEFAULT_UNLOCK_TIME))
 }
\ No newline at end of file
@@ -1,12 +1,185 @@
-use argon2::password_hash::SaltString;
-use rusqlite::{Connection, Result, params};
-use crate::crypto;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::{Connection, OptionalExtension, params};
+use sha2::{Digest, Sha256};
+use crate::crypto::{self, KdfParams};
+use crate::encryption::{self, Algorithm};
+use crate::keystore::KeyStore;
+use crate::secret::{SecretKey, SecretString};
+use crate::totp::{self, OtpAuth};
+use crate::{Error, Result};
+
+// Key every vault was encrypted with before per-user keys existed
+const LEGACY_KEY: [u8; 32] = [42u8; 32];
+
+const PASSWORD_FIELD: &str = "password_encrypted";
+const ACCOUNT_FIELD: &str = "account";
+const USERNAME_FIELD: &str = "username";
+const TOTP_FIELD: &str = "totp_secret";
+const OTP_FIELD: &str = "otp";
+const ATTRIBUTES_FIELD: &str = "attributes";
+
+// Keyring slot holding the key of the vault manifest, below every data key version
+const MANIFEST_KEY_VERSION: i64 = 0;
+
+/// Recovery codes handed out at a time.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+// Recovery codes are random, so the cheaper legacy parameters are plenty against guessing
+const RECOVERY_CODE_PARAMS: KdfParams = KdfParams::LEGACY;
+
+// Associated data of the recovery key wrapped by the password, telling it apart from wrapped data keys
+const RECOVERY_KEY_AAD: &[u8] = b"recovery_key";
+
+// Failed logins allowed in a row before each further attempt has to wait
+const FREE_LOGIN_ATTEMPTS: i64 = 3;
+// Failed logins after which the account is locked for LOCKOUT_SECONDS
+const LOCKOUT_ATTEMPTS: i64 = 10;
+const LOCKOUT_SECONDS: i64 = 15 * 60;
+// Failures older than this are forgotten, which also clears out names that were only ever guessed
+const LOGIN_ATTEMPTS_KEPT_SECONDS: i64 = 24 * 60 * 60;
+// Kinds of failures login_attempts counts apart, so a right password does not clear wrong codes
+const PASSWORD_ATTEMPTS: &str = "password";
+const SECOND_FACTOR_ATTEMPTS: &str = "second factor";
+
+// Salt of the Argon2 run spent on logins of unknown users
+const DUMMY_SALT: &str = "ZHVtbXlsb2dpbnNhbHQ";
+
+// Key version recorded in the header of wrapped keys, which are not encrypted with a data key
+const WRAPPING_KEY_VERSION: u32 = 0;
+
+#[derive(Clone)]
+pub struct Session {
+    pub user_id: i64,
+    pub key: SecretKey,
+    pub key_version: i64,
+    pub algorithm: Algorithm,
+    keys: BTreeMap<i64, SecretKey>,
+    wrapping_key: SecretKey,
+    // Second wrapping of the keyring that survives a forgotten password, if the user created one
+    recovery_key: Option<SecretKey>,
+}
 
 pub fn initialize_db(path: &str) -> Result<Connection> {
     let conn = Connection::open(path)?;
+    migrate(&conn, path)?;
+    // Enforced only once migrated, as rebuilding a table under enforcement would cascade into its children
+    conn.pragma_update(None, "foreign_keys", true)?;
+    // Deleted rows are overwritten with zeros instead of lingering in free pages
+    conn.pragma_update(None, "secure_delete", true)?;
+    Ok(conn)
+}
+
+type Migration = fn(&Connection) -> Result<()>;
+
+// Schema changes in the order they were made; PRAGMA user_version counts those a database has had
+const MIGRATIONS: &[Migration] = &[
+    original_schema,
+    key_salt,
+    wrapped_key,
+    keyring,
+    kdf_params,
+    entries_bound,
+    cipher,
+    encrypted_metadata,
+    totp,
+    recovery_codes,
+    recovery_key,
+    key_file,
+    entry_otp,
+    failed_logins,
+    entry_attributes,
+    vault_manifests,
+    entry_timestamps,
+    totp_reenrollment,
+    password_verifier,
+    login_attempts,
+    drop_blind_index,
+];
 
+// A table and column each change from before versioning added, newest first with the version it brings
+// a database to. Releases then made their changes on every start, so one already having a change had
+// all the earlier ones too
+const UNVERSIONED_MARKERS: &[(i64, &str, &str)] = &[
+    (16, "vault_manifests", "counter"),
+    (15, "passwords", "attributes_encrypted"),
+    (14, "users", "failed_logins"),
+    (13, "passwords", "otp_encrypted"),
+    (12, "users", "key_file_required"),
+    (11, "users", "recovery_key_wrapped"),
+    (10, "recovery_codes", "code_hash"),
+    (9, "users", "totp_secret"),
+    (8, "users", "metadata_encrypted"),
+    (7, "users", "cipher"),
+    (6, "users", "entries_bound"),
+    (5, "users", "kdf_m_cost"),
+    (4, "user_keys", "wrapped_key"),
+    (3, "users", "wrapped_key"),
+    (2, "users", "key_salt"),
+    (1, "users", "password_hash"),
+];
+
+/// Schema version databases are brought up to when opened.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Schema version a database is at, 0 if it predates versioning.
+pub fn schema_version(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+// Runs the migrations a database has not had yet, each in a transaction with its version bump.
+// The database is copied aside first, and the copy removed once every migration committed, as it
+// keeps whatever the migrations encrypt or drop; after a failure it stays to restore the vault from
+fn migrate(conn: &Connection, path: &str) -> Result<()> {
+    let mut version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(Error::NewerSchema(version));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let tables: i64 = conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get(0))?;
+    let backup = if tables > 0 && path != ":memory:" {
+        let backup = format!("{}.v{}.{}.bak", path, version, now());
+        conn.execute("VACUUM INTO ?1", params![backup])?;
+        Some(backup)
+    } else {
+        None
+    };
+
+    // Recorded by the first migration that commits, so a failed one leaves the database as it found it
+    if version == 0 {
+        version = unversioned_version(conn)?;
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.unchecked_transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index as i64 + 1)?;
+        tx.commit()?;
+    }
+
+    if let Some(backup) = backup {
+        // The upgrade went through, so a copy that cannot be removed is no reason to fail opening the vault
+        let _ = std::fs::remove_file(backup);
+    }
+    Ok(())
+}
+
+// Migrations a database from before versioning already had, going by the columns it has
+fn unversioned_version(conn: &Connection) -> Result<i64> {
+    for &(version, table, column) in UNVERSIONED_MARKERS {
+        if column_exists(conn, table, column)? {
+            return Ok(version);
+        }
+    }
+    Ok(0)
+}
+
+fn original_schema(conn: &Connection) -> Result<()> {
     conn.execute(
-    "CREATE TABLE IF NOT EXISTS users (
+    "CREATE TABLE users (
         id INTEGER PRIMARY KEY AUTOINCREMENT,
         username TEXT UNIQUE NOT NULL,
         password_hash TEXT NOT NULL
@@ -15,7 +188,7 @@ pub fn initialize_db(path: &str) -> Result<Connection> {
     )?;
 
     conn.execute(
-        "CREATE TABLE IF NOT EXISTS passwords (
+        "CREATE TABLE passwords (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             user_id INTEGER NOT NULL,
             account TEXT NOT NULL,
@@ -25,94 +198,1621 @@ pub fn initialize_db(path: &str) -> Result<Connection> {
         )",
         [],
     )?;
+    Ok(())
+}
 
-    Ok(conn)
+// Salt of the key derived from the master password, unset for vaults still under the fixed legacy key
+fn key_salt(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN key_salt TEXT", [])?;
+    Ok(())
+}
+
+// A random data key wrapped with the password-derived one
+fn wrapped_key(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN wrapped_key BLOB", [])?;
+    Ok(())
+}
+
+// Data keys are kept per version so they can be rotated; single wrapped keys become version 1
+fn keyring(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN key_version INTEGER", [])?;
+    conn.execute("ALTER TABLE users ADD COLUMN rotated_at INTEGER", [])?;
+    conn.execute("ALTER TABLE users ADD COLUMN rotation_interval_days INTEGER NOT NULL DEFAULT 90", [])?;
+    conn.execute("ALTER TABLE passwords ADD COLUMN key_version INTEGER NOT NULL DEFAULT 1", [])?;
+    conn.execute(
+        "CREATE TABLE user_keys (
+            user_id INTEGER NOT NULL,
+            version INTEGER NOT NULL,
+            wrapped_key BLOB NOT NULL,
+            created_at INTEGER NOT NULL,
+            PRIMARY KEY (user_id, version),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "INSERT INTO user_keys (user_id, version, wrapped_key, created_at)
+         SELECT id, 1, wrapped_key, ?1 FROM users WHERE wrapped_key IS NOT NULL",
+        params![now()],
+    )?;
+    conn.execute(
+        "UPDATE users SET key_version = 1, rotated_at = ?1 WHERE wrapped_key IS NOT NULL",
+        params![now()],
+    )?;
+    conn.execute("ALTER TABLE users DROP COLUMN wrapped_key", [])?;
+    Ok(())
+}
+
+// Defaults match KdfParams::LEGACY, which existing accounts were created with
+fn kdf_params(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN kdf_algorithm TEXT NOT NULL DEFAULT 'argon2id'", [])?;
+    conn.execute("ALTER TABLE users ADD COLUMN kdf_m_cost INTEGER NOT NULL DEFAULT 19456", [])?;
+    conn.execute("ALTER TABLE users ADD COLUMN kdf_t_cost INTEGER NOT NULL DEFAULT 2", [])?;
+    conn.execute("ALTER TABLE users ADD COLUMN kdf_p_cost INTEGER NOT NULL DEFAULT 1", [])?;
+    Ok(())
+}
+
+// Whether the user's ciphertexts carry their row as associated data, set once the next unlock rewrote them
+fn entries_bound(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN entries_bound INTEGER NOT NULL DEFAULT 0", [])?;
+    Ok(())
+}
+
+fn cipher(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN cipher TEXT NOT NULL DEFAULT 'aes-256-gcm'", [])?;
+    Ok(())
+}
+
+// account and username stay empty once encrypted; they only hold data of vaults not yet unlocked since
+fn encrypted_metadata(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN metadata_encrypted INTEGER NOT NULL DEFAULT 0", [])?;
+    conn.execute("ALTER TABLE passwords ADD COLUMN account_encrypted BLOB", [])?;
+    conn.execute("ALTER TABLE passwords ADD COLUMN username_encrypted BLOB", [])?;
+    conn.execute("ALTER TABLE passwords ADD COLUMN account_index BLOB", [])?;
+    conn.execute("ALTER TABLE passwords ADD COLUMN username_index BLOB", [])?;
+    conn.execute("CREATE INDEX passwords_account_index ON passwords (user_id, account_index)", [])?;
+    Ok(())
+}
+
+fn totp(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN totp_secret BLOB", [])?;
+    conn.execute("ALTER TABLE users ADD COLUMN totp_last_step INTEGER", [])?;
+    Ok(())
+}
+
+fn recovery_codes(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN credential_reset INTEGER NOT NULL DEFAULT 0", [])?;
+    conn.execute(
+        "CREATE TABLE recovery_codes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            code_hash TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+// The keyring wrapped a second time, with the key behind the recovery words
+fn recovery_key(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN recovery_key_wrapped BLOB", [])?;
+    conn.execute("ALTER TABLE user_keys ADD COLUMN recovery_wrapped_key BLOB", [])?;
+    Ok(())
+}
+
+fn key_file(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN key_file_required INTEGER NOT NULL DEFAULT 0", [])?;
+    Ok(())
+}
+
+fn entry_otp(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE passwords ADD COLUMN otp_encrypted BLOB", [])?;
+    Ok(())
+}
+
+fn failed_logins(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN failed_logins INTEGER NOT NULL DEFAULT 0", [])?;
+    conn.execute("ALTER TABLE users ADD COLUMN last_failed_login INTEGER", [])?;
+    Ok(())
+}
+
+// Lookup attributes of entries stored through the Secret Service provider
+fn entry_attributes(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE passwords ADD COLUMN attributes_encrypted BLOB", [])?;
+    Ok(())
+}
+
+// Entries of each vault as of its last change, so changes made behind the library's back show up
+fn vault_manifests(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE vault_manifests (
+            user_id INTEGER PRIMARY KEY,
+            counter INTEGER NOT NULL,
+            entries BLOB NOT NULL,
+            mac BLOB NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+// Entries record when they were added and last changed, left unknown for those already stored
+fn entry_timestamps(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE passwords ADD COLUMN created_at INTEGER", [])?;
+    conn.execute("ALTER TABLE passwords ADD COLUMN updated_at INTEGER", [])?;
+    Ok(())
+}
+
+// A used recovery code asks for a new authenticator rather than a new master password.
+// Users still owing a reset lost their authenticator the same way, so they owe the enrollment instead
+fn totp_reenrollment(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users RENAME COLUMN credential_reset TO totp_reenrollment", [])?;
+    Ok(())
+}
+
+// The password hash covers the password alone, so it can be checked before asking for a key file.
+// Accounts with a key file hashed the composite of both until their next unlock rewrites it
+fn password_verifier(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE users ADD COLUMN hash_includes_key_file INTEGER NOT NULL DEFAULT 0", [])?;
+    conn.execute("UPDATE users SET hash_includes_key_file = key_file_required", [])?;
+    Ok(())
+}
+
+// Failed logins move out of users into a table of their own: they are counted per name tried rather than per
+// account, so unknown names are throttled alike, and a row of users could not hold names nobody registered.
+// Names are stored hashed, so one tried against a deleted account does not keep it in the file, and rows
+// older than LOGIN_ATTEMPTS_KEPT_SECONDS are pruned on every check, so guessed names cannot pile up.
+fn login_attempts(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE login_attempts (
+            username_digest BLOB PRIMARY KEY,
+            failed_logins INTEGER NOT NULL,
+            last_failed_login INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    let mut stmt = conn.prepare("SELECT username, failed_logins, last_failed_login FROM users WHERE last_failed_login IS NOT NULL")?;
+    let attempts = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (username, failed_logins, last_failed_login) in attempts {
+        conn.execute(
+            "INSERT INTO login_attempts (username_digest, failed_logins, last_failed_login) VALUES (?1, ?2, ?3)",
+            params![attempts_digest(PASSWORD_ATTEMPTS, &username), failed_logins, last_failed_login],
+        )?;
+    }
+
+    conn.execute("ALTER TABLE users DROP COLUMN failed_logins", [])?;
+    conn.execute("ALTER TABLE users DROP COLUMN last_failed_login", [])?;
+    Ok(())
+}
+
+// Keyed hashes of account and username told which entries share them, and nothing looks entries up by them
+fn drop_blind_index(conn: &Connection) -> Result<()> {
+    conn.execute("DROP INDEX passwords_account_index", [])?;
+    conn.execute("ALTER TABLE passwords DROP COLUMN account_index", [])?;
+    conn.execute("ALTER TABLE passwords DROP COLUMN username_index", [])?;
+    Ok(())
+}
+
+// Key of the login_attempts row counting one kind of failures of `username`
+fn attempts_digest(attempts: &str, username: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(attempts.as_bytes());
+    hasher.update([0]);
+    hasher.update(username.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(columns.iter().any(|name| name == column))
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Associated data binding a ciphertext to its owner, row and column,
+// so blobs copied between rows or users no longer decrypt
+fn entry_aad(user_id: i64, entry_id: i64, field: &str) -> Vec<u8> {
+    format!("{}:{}:{}", user_id, entry_id, field).into_bytes()
+}
+
+// An entry as stored, every field still encrypted
+struct StoredEntry {
+    id: i64,
+    account: Vec<u8>,
+    username: Vec<u8>,
+    password: Vec<u8>,
+    key_version: i64,
+    otp: Option<Vec<u8>>,
+    attributes: Option<Vec<u8>>,
+    created_at: Option<i64>,
+    updated_at: Option<i64>,
+}
+
+impl StoredEntry {
+    const COLUMNS: &'static str = "id, account_encrypted, username_encrypted, password_encrypted, key_version, otp_encrypted, \
+         attributes_encrypted, created_at, updated_at";
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(StoredEntry {
+            id: row.get(0)?,
+            account: row.get(1)?,
+            username: row.get(2)?,
+            password: row.get(3)?,
+            key_version: row.get(4)?,
+            otp: row.get(5)?,
+            attributes: row.get(6)?,
+            created_at: row.get(7)?,
+            updated_at: row.get(8)?,
+        })
+    }
+
+    fn decrypt(self, session: &Session) -> Result<VaultEntry> {
+        let otp = self
+            .otp
+            .map(|encrypted| OtpAuth::parse(&decrypt_field(session, &encrypted, self.key_version, self.id, OTP_FIELD)?))
+            .transpose()?;
+        Ok(VaultEntry {
+            id: self.id,
+            account: decrypt_field(session, &self.account, self.key_version, self.id, ACCOUNT_FIELD)?.to_string(),
+            username: decrypt_field(session, &self.username, self.key_version, self.id, USERNAME_FIELD)?.to_string(),
+            password: decrypt_field(session, &self.password, self.key_version, self.id, PASSWORD_FIELD)?,
+            otp,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        })
+    }
+}
+
+/// A decrypted entry of the vault, addressed by its row id.
+#[derive(Clone)]
+pub struct VaultEntry {
+    pub id: i64,
+    pub account: String,
+    pub username: String,
+    pub password: SecretString,
+    pub otp: Option<OtpAuth>,
+    /// Unix time the entry was added, `None` for entries stored before it was recorded.
+    pub created_at: Option<i64>,
+    /// Unix time the entry was last changed, `None` for entries stored before it was recorded.
+    pub updated_at: Option<i64>,
+}
+
+fn encrypt_field(session: &Session, key: &[u8], key_version: i64, entry_id: i64, field: &str, value: &str) -> Result<Vec<u8>> {
+    let aad = entry_aad(session.user_id, entry_id, field);
+    encryption::encrypt(value, key, session.algorithm, key_version as u32, &aad)
 }
 
-pub fn insert_password(
+fn decrypt_field(session: &Session, encrypted: &[u8], key_version: i64, entry_id: i64, field: &str) -> Result<SecretString> {
+    let key = entry_key(session, encrypted, key_version).ok_or(Error::Aead)?;
+    encryption::decrypt(encrypted, key, &entry_aad(session.user_id, entry_id, field))
+}
+
+// Picks the data key from the ciphertext header, falling back to the row's key_version for headerless blobs
+fn entry_key<'a>(session: &'a Session, encrypted: &[u8], key_version: i64) -> Option<&'a SecretKey> {
+    let version = encryption::read_header(encrypted)
+        .map(|header| header.key_version as i64)
+        .unwrap_or(key_version);
+
+    session.keys.get(&version)
+}
+
+// Writes the encrypted account, username and password of an existing row with the current key
+fn write_entry(
     conn: &Connection,
+    session: &Session,
+    entry_id: i64,
     account: &str,
     username: &str,
-    password_encrypted: &[u8],
-    user_id: &i64,
+    password: &str,
 ) -> Result<()> {
+    let (key, version) = (&session.key, session.key_version);
     conn.execute(
-        "INSERT INTO passwords (user_id, account, username, password_encrypted) VALUES (?1, ?2, ?3, ?4)",
-        params![user_id, account, username, password_encrypted],
+        "UPDATE passwords SET account = '', username = '', account_encrypted = ?1, username_encrypted = ?2,
+         password_encrypted = ?3, key_version = ?4, updated_at = ?5 WHERE id = ?6",
+        params![
+            encrypt_field(session, key, version, entry_id, ACCOUNT_FIELD, account)?,
+            encrypt_field(session, key, version, entry_id, USERNAME_FIELD, username)?,
+            encrypt_field(session, key, version, entry_id, PASSWORD_FIELD, password)?,
+            version,
+            now(),
+            entry_id
+        ],
     )?;
-
     Ok(())
 }
 
-pub fn get_passwords(conn: &Connection, user_id: &i64) -> Result<Vec<(String, String, Vec<u8>)>> {
-    let mut stmt = conn.prepare("SELECT account, username, password_encrypted FROM passwords WHERE user_id = ?1")?;
+// Adds an entry, inside a transaction of the caller
+fn add_entry(conn: &Connection, session: &Session, account: &str, username: &str, password: &str) -> Result<i64> {
+    // The row id is part of the associated data, so the ciphertexts are written once it is known
+    conn.execute(
+        "INSERT INTO passwords (user_id, account, username, password_encrypted, key_version, created_at)
+         VALUES (?1, '', '', x'', ?2, ?3)",
+        params![session.user_id, session.key_version, now()],
+    )?;
+    let entry_id = conn.last_insert_rowid();
+    write_entry(conn, session, entry_id, account, username, password)?;
 
-    let result = stmt
-        .query_map(params![user_id], |row| {
-            Ok((
-                row.get(0)?, // account
-                row.get(1)?, // username
-                row.get(2)?, // password_encrypted
-            ))
-        })?
-        .collect();
+    Ok(entry_id)
+}
 
-    result
+fn stored_entries(conn: &Connection, user_id: i64) -> Result<Vec<StoredEntry>> {
+    Ok(conn
+        .prepare(&format!("SELECT {} FROM passwords WHERE user_id = ?1 ORDER BY id", StoredEntry::COLUMNS))?
+        .query_map(params![user_id], StoredEntry::from_row)?
+        .collect::<rusqlite::Result<_>>()?)
 }
 
-pub fn delete_vault(conn: &Connection, account: &str, username: &str, user_id: &i64) -> rusqlite::Result<()> {
+fn write_entry_otp(conn: &Connection, session: &Session, entry_id: i64, otp: Option<&OtpAuth>) -> Result<()> {
+    let encrypted = otp
+        .map(|otp| SecretString::from(otp.to_uri()))
+        .map(|uri| encrypt_field(session, &session.key, session.key_version, entry_id, OTP_FIELD, &uri))
+        .transpose()?;
     conn.execute(
-        "DELETE FROM passwords WHERE account = ?1 AND username = ?2 AND user_id = ?3",
-        params![account, username, user_id],
+        "UPDATE passwords SET otp_encrypted = ?1, updated_at = ?2 WHERE id = ?3",
+        params![encrypted, now(), entry_id],
     )?;
     Ok(())
 }
 
-pub fn update_vault(
+/// The entry with this id, if it belongs to the user.
+pub fn get_entry(conn: &Connection, session: &Session, entry_id: i64) -> Result<VaultEntry> {
+    let stored = conn.query_row(
+        &format!("SELECT {} FROM passwords WHERE id = ?1 AND user_id = ?2", StoredEntry::COLUMNS),
+        params![entry_id, session.user_id],
+        StoredEntry::from_row,
+    )?;
+    stored.decrypt(session)
+}
+
+/// Every entry of the user, in the order they were added.
+///
+/// Entries that do not decrypt are left out, `unreadable_entries` lists them.
+pub fn list_entries(conn: &Connection, session: &Session) -> Result<Vec<VaultEntry>> {
+    Ok(stored_entries(conn, session.user_id)?
+        .into_iter()
+        .filter_map(|entry| entry.decrypt(session).ok())
+        .collect())
+}
+
+/// Entries whose account contains `query`, ignoring case.
+///
+/// Accounts are stored encrypted, so every entry is decrypted to be matched here.
+pub fn search_entries(conn: &Connection, session: &Session, query: &str) -> Result<Vec<VaultEntry>> {
+    let query = query.to_lowercase();
+    let mut entries = list_entries(conn, session)?;
+    entries.retain(|entry| entry.account.to_lowercase().contains(&query));
+    Ok(entries)
+}
+
+/// Ids of the user's entries that no longer decrypt, such as those a move off an older key scheme could not read.
+///
+/// They are left in the database as they are, so a key found later could still open them.
+pub fn unreadable_entries(conn: &Connection, session: &Session) -> Result<Vec<i64>> {
+    Ok(stored_entries(conn, session.user_id)?
+        .into_iter()
+        .filter_map(|entry| {
+            let id = entry.id;
+            entry.decrypt(session).is_err().then_some(id)
+        })
+        .collect())
+}
+
+/// Adds an entry and returns it as stored, with its id and timestamps.
+pub fn insert_entry(
+    conn: &Connection,
+    session: &Session,
+    account: &str,
+    username: &str,
+    password: &str,
+    otp: Option<&OtpAuth>,
+) -> Result<VaultEntry> {
+    let tx = conn.unchecked_transaction()?;
+    let entry_id = add_entry(&tx, session, account, username, password)?;
+    if otp.is_some() {
+        write_entry_otp(&tx, session, entry_id, otp)?;
+    }
+    update_manifest(&tx, session, 0)?;
+    tx.commit()?;
+
+    get_entry(conn, session, entry_id)
+}
+
+/// Writes the fields of `entry` over the stored entry with its id and returns it as stored.
+pub fn update_entry(conn: &Connection, session: &Session, entry: &VaultEntry) -> Result<VaultEntry> {
+    let tx = conn.unchecked_transaction()?;
+    owned_entry(&tx, session, entry.id)?;
+    write_entry(&tx, session, entry.id, &entry.account, &entry.username, &entry.password)?;
+    write_entry_otp(&tx, session, entry.id, entry.otp.as_ref())?;
+    update_manifest(&tx, session, 0)?;
+    tx.commit()?;
+
+    get_entry(conn, session, entry.id)
+}
+
+pub fn delete_entry(conn: &Connection, session: &Session, entry_id: i64) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    owned_entry(&tx, session, entry_id)?;
+    tx.execute("DELETE FROM passwords WHERE id = ?1", params![entry_id])?;
+    update_manifest(&tx, session, 0)?;
+    Ok(tx.commit()?)
+}
+
+/// An entry as the Secret Service provider sees it, addressed by its row id.
+pub(crate) struct SecretItem {
+    pub id: i64,
+    pub label: String,
+    pub username: String,
+    /// Lookup attributes given by the application that stored the item, `None` for entries made in the vault itself.
+    pub attributes: Option<HashMap<String, String>>,
+}
+
+impl SecretItem {
+    // Entries made in the vault are found by the fields they have
+    pub fn lookup_attributes(&self) -> HashMap<String, String> {
+        self.attributes.clone().unwrap_or_else(|| {
+            HashMap::from([(ACCOUNT_FIELD.to_string(), self.label.clone()), (USERNAME_FIELD.to_string(), self.username.clone())])
+        })
+    }
+}
+
+pub(crate) fn secret_items(conn: &Connection, session: &Session) -> Result<Vec<SecretItem>> {
+    let rows: Vec<StoredEntry> = conn
+        .prepare(&format!("SELECT {} FROM passwords WHERE user_id = ?1", StoredEntry::COLUMNS))?
+        .query_map(params![session.user_id], StoredEntry::from_row)?
+        .collect::<rusqlite::Result<_>>()?;
+
+    rows.into_iter()
+        .map(|entry| {
+            let attributes = entry
+                .attributes
+                .map(|encrypted| decrypt_field(session, &encrypted, entry.key_version, entry.id, ATTRIBUTES_FIELD))
+                .transpose()?
+                .map(|encoded| decode_attributes(&encoded));
+            Ok(SecretItem {
+                id: entry.id,
+                label: decrypt_field(session, &entry.account, entry.key_version, entry.id, ACCOUNT_FIELD)?.to_string(),
+                username: decrypt_field(session, &entry.username, entry.key_version, entry.id, USERNAME_FIELD)?.to_string(),
+                attributes,
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn secret_item_password(conn: &Connection, session: &Session, entry_id: i64) -> Result<SecretString> {
+    let (encrypted, key_version): (Vec<u8>, i64) = conn.query_row(
+        "SELECT password_encrypted, key_version FROM passwords WHERE id = ?1 AND user_id = ?2",
+        params![entry_id, session.user_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    decrypt_field(session, &encrypted, key_version, entry_id, PASSWORD_FIELD)
+}
+
+/// Stores an item of another application; `entry_id` of `None` adds a new entry. Returns the entry id.
+pub(crate) fn write_secret_item(
+    conn: &Connection,
+    session: &Session,
+    entry_id: Option<i64>,
+    label: &str,
+    username: &str,
+    password: &str,
+    attributes: &HashMap<String, String>,
+) -> Result<i64> {
+    let tx = conn.unchecked_transaction()?;
+    let entry_id = match entry_id {
+        Some(entry_id) => {
+            owned_entry(&tx, session, entry_id)?;
+            write_entry(&tx, session, entry_id, label, username, password)?;
+            entry_id
+        }
+        None => add_entry(&tx, session, label, username, password)?,
+    };
+    let encrypted = encrypt_field(session, &session.key, session.key_version, entry_id, ATTRIBUTES_FIELD, &encode_attributes(attributes))?;
+    tx.execute("UPDATE passwords SET attributes_encrypted = ?1 WHERE id = ?2", params![encrypted, entry_id])?;
+    update_manifest(&tx, session, 0)?;
+    tx.commit()?;
+
+    Ok(entry_id)
+}
+
+pub(crate) fn delete_secret_item(conn: &Connection, session: &Session, entry_id: i64) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    owned_entry(&tx, session, entry_id)?;
+    tx.execute("DELETE FROM passwords WHERE id = ?1", params![entry_id])?;
+    update_manifest(&tx, session, 0)?;
+    Ok(tx.commit()?)
+}
+
+fn owned_entry(conn: &Connection, session: &Session, entry_id: i64) -> Result<()> {
+    conn.query_row(
+        "SELECT 1 FROM passwords WHERE id = ?1 AND user_id = ?2",
+        params![entry_id, session.user_id],
+        |_| Ok(()),
+    )?;
+    Ok(())
+}
+
+// D-Bus strings cannot hold NUL, so it separates keys and values
+fn encode_attributes(attributes: &HashMap<String, String>) -> SecretString {
+    let sorted: BTreeMap<_, _> = attributes.iter().collect();
+    let mut encoded = SecretString::default();
+    for (name, value) in sorted {
+        encoded.push_str(name);
+        encoded.push('\0');
+        encoded.push_str(value);
+        encoded.push('\0');
+    }
+    encoded
+}
+
+fn decode_attributes(encoded: &str) -> HashMap<String, String> {
+    let mut parts = encoded.split('\0');
+    let mut attributes = HashMap::new();
+    while let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+        attributes.insert(name.to_string(), value.to_string());
+    }
+    attributes
+}
+
+/// Checks the entries of the user against the manifest sealed by the last change the library made.
+///
+/// `last_seen` is the highest manifest counter this device has seen, kept outside the database;
+/// a lower counter means the database was rolled back to an older copy. A vault without a manifest
+/// gets one sealed as it is, unless `last_seen` shows it had one. Returns the current counter.
+pub fn verify_manifest(conn: &Connection, session: &Session, last_seen: Option<i64>) -> Result<i64> {
+    let stored: Option<(i64, Vec<u8>, Vec<u8>)> = conn
+        .query_row(
+            "SELECT counter, entries, mac FROM vault_manifests WHERE user_id = ?1",
+            params![session.user_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    let Some((counter, entries, mac)) = stored else {
+        if last_seen.is_some() {
+            return Err(Error::Tampered("the manifest was removed".to_string()));
+        }
+        return update_manifest(conn, session, 0);
+    };
+
+    let key = session.keys.get(&MANIFEST_KEY_VERSION).ok_or(Error::Aead)?;
+    if !crypto::verify_manifest_mac(key, session.user_id, counter, &entries, &mac) {
+        return Err(Error::Tampered("the manifest does not authenticate".to_string()));
+    }
+    if let Some(seen) = last_seen.filter(|&seen| seen > counter) {
+        return Err(Error::Tampered(format!("the vault was rolled back from change {} to change {}", seen, counter)));
+    }
+
+    let expected: BTreeMap<i64, [u8; 32]> = decode_manifest(&entries)?;
+    let actual: BTreeMap<i64, [u8; 32]> = manifest_entries(conn, session.user_id)?.into_iter().collect();
+    let removed = expected.keys().filter(|id| !actual.contains_key(id)).count();
+    let added = actual.keys().filter(|id| !expected.contains_key(id)).count();
+    let altered = expected.iter().filter(|(id, digest)| actual.get(id).is_some_and(|actual| actual != *digest)).count();
+    if removed + added + altered > 0 {
+        return Err(Error::Tampered(format!("{} entries removed, {} added and {} altered", removed, added, altered)));
+    }
+
+    Ok(counter)
+}
+
+/// Seals the vault as it is now into a new manifest, past `last_seen`, after the user chose to trust it.
+pub fn reseal_manifest(conn: &Connection, session: &Session, last_seen: Option<i64>) -> Result<i64> {
+    update_manifest(conn, session, last_seen.unwrap_or(0))
+}
+
+/// Counter of the user's manifest, which grows with every change to the vault.
+pub fn manifest_counter(conn: &Connection, user_id: i64) -> Result<Option<i64>> {
+    Ok(conn
+        .query_row("SELECT counter FROM vault_manifests WHERE user_id = ?1", params![user_id], |row| row.get(0))
+        .optional()?)
+}
+
+// Seals the current entries of the user under a counter above both the stored one and `floor`
+fn update_manifest(conn: &Connection, session: &Session, floor: i64) -> Result<i64> {
+    let key = session.keys.get(&MANIFEST_KEY_VERSION).ok_or(Error::Aead)?;
+    let counter = manifest_counter(conn, session.user_id)?.unwrap_or(0).max(floor) + 1;
+
+    let mut entries = Vec::new();
+    for (id, digest) in manifest_entries(conn, session.user_id)? {
+        entries.extend_from_slice(&id.to_le_bytes());
+        entries.extend_from_slice(&digest);
+    }
+    let mac = crypto::manifest_mac(key, session.user_id, counter, &entries);
+
+    conn.execute(
+        "INSERT INTO vault_manifests (user_id, counter, entries, mac) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (user_id) DO UPDATE SET counter = excluded.counter, entries = excluded.entries, mac = excluded.mac",
+        params![session.user_id, counter, entries, mac],
+    )?;
+    Ok(counter)
+}
+
+// Row id and digest of every entry of the user, ordered by id
+fn manifest_entries(conn: &Connection, user_id: i64) -> Result<Vec<(i64, [u8; 32])>> {
+    let rows: Vec<StoredEntry> = conn
+        .prepare(&format!("SELECT {} FROM passwords WHERE user_id = ?1 ORDER BY id", StoredEntry::COLUMNS))?
+        .query_map(params![user_id], StoredEntry::from_row)?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(rows
+        .iter()
+        .map(|entry| {
+            let key_version = entry.key_version.to_le_bytes();
+            let digest = crypto::entry_digest(&[
+                Some(&entry.account),
+                Some(&entry.username),
+                Some(&entry.password),
+                Some(&key_version),
+                entry.otp.as_deref(),
+                entry.attributes.as_deref(),
+            ]);
+            (entry.id, digest)
+        })
+        .collect())
+}
+
+fn decode_manifest(entries: &[u8]) -> Result<BTreeMap<i64, [u8; 32]>> {
+    // Row id followed by the digest of the entry
+    const ENTRY_LEN: usize = 8 + 32;
+    if !entries.len().is_multiple_of(ENTRY_LEN) {
+        return Err(Error::Encoding("manifest length".to_string()));
+    }
+    Ok(entries
+        .chunks_exact(ENTRY_LEN)
+        .map(|chunk| {
+            let (id, digest) = chunk.split_at(8);
+            (i64::from_le_bytes(id.try_into().expect("8 bytes")), digest.try_into().expect("32 bytes"))
+        })
+        .collect())
+}
+
+pub fn login_user(conn: &Connection, username: &str, password: &str) -> Result<Session> {
+    login_user_with_key_file(conn, username, password, None)
+}
+
+/// Logs in with the master password and, for accounts protected by one, the contents of the key file.
+///
+/// Fails with `Error::KeyFileRequired` when the account has a key file and `key_file` is `None`,
+/// once the password proved right; a wrong password fails with `Error::Authentication` either way.
+/// A key file given for an account without one is ignored.
+///
+/// After `FREE_LOGIN_ATTEMPTS` failures in a row every attempt has to wait twice as long as the one
+/// before, and `LOCKOUT_ATTEMPTS` failures lock the username for `LOCKOUT_SECONDS`; until then the
+/// password is not even checked and `Error::Throttled` is returned.
+///
+/// Unknown usernames are throttled the same way, cost an Argon2 run with the parameters accounts are
+/// registered and upgraded with on this machine and fail with the same `Error::Authentication` as a
+/// wrong password. Accounts on weaker parameters answer a wrong password no sooner than that.
+pub fn login_user_with_key_file(conn: &Connection, username: &str, password: &str, key_file: Option<&[u8]>) -> Result<Session> {
+    throttled(conn, PASSWORD_ATTEMPTS, username, || {
+        let user_id: Option<i64> = conn
+            .query_row("SELECT id FROM users WHERE username = ?1", params![username], |row| row.get(0))
+            .optional()?;
+        match user_id {
+            Some(user_id) => open_vault(conn, user_id, password, key_file),
+            None => {
+                crypto::derive_key_from_password(password, DUMMY_SALT, &crypto::calibrated_params())?;
+                Err(Error::Authentication)
+            }
+        }
+    })
+}
+
+/// Saves the key the master password unlocks into `store`, so this device can log in with `login_remembered`.
+///
+/// Changing the master password or the key file leaves the saved key stale; it is then forgotten on its next use.
+pub fn remember_device(store: &dyn KeyStore, username: &str, session: &Session) -> Result<()> {
+    store.save(username, &session.wrapping_key)
+}
+
+pub fn forget_device(store: &dyn KeyStore, username: &str) -> Result<()> {
+    store.delete(username)
+}
+
+pub fn device_remembered(store: &dyn KeyStore, username: &str) -> Result<bool> {
+    Ok(store.load(username)?.is_some())
+}
+
+/// Logs in with the key `remember_device` saved, without the master password.
+///
+/// Returns `Ok(None)` when `store` has nothing for `username`. A saved key that no longer opens the
+/// vault is deleted from `store` and reported as `Error::Authentication`. The second factor is not
+/// checked here, just like in `login_user`.
+pub fn login_remembered(conn: &Connection, store: &dyn KeyStore, username: &str) -> Result<Option<Session>> {
+    let Some(wrapping_key) = store.load(username)? else {
+        return Ok(None);
+    };
+
+    let user: Option<(i64, Option<i64>, String)> = conn
+        .query_row(
+            "SELECT id, key_version, cipher FROM users WHERE username = ?1",
+            params![username],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    let unlocked = match user {
+        Some((user_id, Some(key_version), cipher)) => unlock_keyring(conn, user_id, key_version, cipher.parse()?, wrapping_key),
+        _ => Err(Error::Authentication),
+    };
+
+    match unlocked {
+        Ok(session) => Ok(Some(session)),
+        Err(Error::Aead | Error::Authentication) => {
+            store.delete(username)?;
+            Err(Error::Authentication)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Seconds a login has to wait after the last of `failed_logins` failures in a row
+fn login_delay(failed_logins: i64) -> i64 {
+    if failed_logins >= LOCKOUT_ATTEMPTS {
+        LOCKOUT_SECONDS
+    } else if failed_logins >= FREE_LOGIN_ATTEMPTS {
+        1 << (failed_logins - FREE_LOGIN_ATTEMPTS + 1)
+    } else {
+        0
+    }
+}
+
+// What the keyring is wrapped with: the password itself, or the composite with the key file if the user set one
+fn unlock_secret(password: &str, key_file: Option<&[u8]>) -> SecretString {
+    match key_file {
+        Some(key_file) => crypto::composite_password(password, key_file),
+        None => password.into(),
+    }
+}
+
+// The key file the user has to unlock with, dropping one given for an account without
+fn required_key_file<'a>(conn: &Connection, user_id: i64, key_file: Option<&'a [u8]>) -> Result<Option<&'a [u8]>> {
+    let required = key_file_required(conn, user_id)?;
+    Ok(key_file.filter(|_| required))
+}
+
+// Every check of the master password goes through here, so none of them guesses faster than a login
+fn unlock_user(conn: &Connection, user_id: i64, password: &str, key_file: Option<&[u8]>) -> Result<Session> {
+    let username = username_of(conn, user_id)?;
+    throttled(conn, PASSWORD_ATTEMPTS, &username, || open_vault(conn, user_id, password, key_file))
+}
+
+fn username_of(conn: &Connection, user_id: i64) -> Result<String> {
+    conn.query_row("SELECT username FROM users WHERE id = ?1", params![user_id], |row| row.get(0))
+        .optional()?
+        .ok_or(Error::Authentication)
+}
+
+// Runs `unlock` unless `username` has to wait after failed `attempts`, recording a wrong secret against it
+fn throttled<T>(conn: &Connection, attempts: &str, username: &str, unlock: impl FnOnce() -> Result<T>) -> Result<T> {
+    conn.execute(
+        "DELETE FROM login_attempts WHERE last_failed_login < ?1",
+        params![now() - LOGIN_ATTEMPTS_KEPT_SECONDS],
+    )?;
+
+    let digest = attempts_digest(attempts, username);
+    let attempts: Option<(i64, i64)> = conn
+        .query_row(
+            "SELECT failed_logins, last_failed_login FROM login_attempts WHERE username_digest = ?1",
+            params![digest],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+
+    if let Some((failed_logins, last_failed_login)) = attempts {
+        let wait = login_delay(failed_logins) - (now() - last_failed_login);
+        if wait > 0 {
+            return Err(Error::Throttled(wait as u64));
+        }
+    }
+
+    match unlock() {
+        Ok(unlocked) => {
+            conn.execute("DELETE FROM login_attempts WHERE username_digest = ?1", params![digest])?;
+            Ok(unlocked)
+        }
+        Err(Error::Authentication) => {
+            conn.execute(
+                "INSERT INTO login_attempts (username_digest, failed_logins, last_failed_login) VALUES (?1, 1, ?2)
+                 ON CONFLICT (username_digest) DO UPDATE SET failed_logins = failed_logins + 1, last_failed_login = ?2",
+                params![digest, now()],
+            )?;
+            Err(Error::Authentication)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn open_vault(conn: &Connection, user_id: i64, password: &str, key_file: Option<&[u8]>) -> Result<Session> {
+    let (hash, key_salt, key_version, algorithm, m_cost, t_cost, p_cost, cipher): (String, Option<String>, Option<i64>, String, u32, u32, u32, String) = conn
+        .query_row(
+            "SELECT password_hash, key_salt, key_version, kdf_algorithm, kdf_m_cost, kdf_t_cost, kdf_p_cost, cipher FROM users WHERE id = ?1",
+            params![user_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?)),
+        )
+        .optional()?
+        .ok_or(Error::Authentication)?;
+    let (key_file_required, hash_includes_key_file): (bool, bool) = conn.query_row(
+        "SELECT key_file_required, hash_includes_key_file FROM users WHERE id = ?1",
+        params![user_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let algorithm = algorithm.parse().map_err(|err| Error::Encoding(format!("kdf algorithm: {}", err)))?;
+    let params = KdfParams { algorithm, m_cost, t_cost, p_cost };
+    let cipher: Algorithm = cipher.parse()?;
+    let key_file = key_file.filter(|_| key_file_required);
+
+    // The password is checked first, so only whoever knows it learns that a key file is missing
+    match (hash_includes_key_file, key_file) {
+        (false, _) => {
+            if !crypto::verify_password(&hash, password)? {
+                return Err(wrong_password(password, &params));
+            }
+            if key_file_required && key_file.is_none() {
+                return Err(Error::KeyFileRequired);
+            }
+        }
+        (true, Some(key_file)) => {
+            if !crypto::verify_password(&hash, &crypto::composite_password(password, key_file))? {
+                return Err(wrong_password(password, &params));
+            }
+        }
+        // A hash of the composite cannot be checked without the file. Spending the same work keeps
+        // the answer from coming back early, it is rewritten on the next unlock with the file
+        (true, None) => {
+            crypto::derive_key_from_password(password, DUMMY_SALT, &params)?;
+            return Err(Error::KeyFileRequired);
+        }
+    }
+    let secret = unlock_secret(password, key_file);
+
+    let mut session = match (key_salt, key_version) {
+        (Some(salt), Some(key_version)) => {
+            let wrapping_key = crypto::derive_key_from_password(&secret, &salt, &params)?;
+            // The password was right, so a keyring that does not open means the wrong key file
+            let session = unlock_keyring(conn, user_id, key_version, cipher, wrapping_key).map_err(|err| match err {
+                Error::Aead if key_file.is_some() => Error::Authentication,
+                err => err,
+            })?;
+            bind_entries(conn, &session)?;
+            session
+        }
+        // Vault encrypted directly with the password-derived key
+        (Some(salt), None) => {
+            let old_key = crypto::derive_key_from_password(password, &salt, &params)?;
+            migrate_vault(conn, user_id, password, &params, cipher, &old_key)?
+        }
+        (None, _) => migrate_vault(conn, user_id, password, &params, cipher, &LEGACY_KEY)?,
+    };
+    encrypt_metadata(conn, &mut session)?;
+
+    let weak = params.is_weaker_than(&KdfParams::default());
+    if weak || hash_includes_key_file {
+        // A failed upgrade leaves the old parameters in place and is retried on the next login
+        let params = if weak { crypto::calibrated_params() } else { params };
+        set_master_password(conn, &mut session, password, key_file, &params).ok();
+    }
+
+    Ok(session)
+}
+
+// Makes up the difference when the account's parameters are cheaper than those unknown names are
+// checked with, so how soon a wrong password is answered does not tell the accounts apart
+fn wrong_password(password: &str, params: &KdfParams) -> Error {
+    let target = crypto::calibrated_params();
+    if params.is_weaker_than(&target) {
+        crypto::derive_key_from_password(password, DUMMY_SALT, &target).ok();
+    }
+    Error::Authentication
+}
+
+fn unlock_keyring(
     conn: &Connection,
-    old_account: &str,
-    old_username: &str,
-    new_account: &str,
-    new_username: &str,
-    new_encrypted_password: &[u8],
-    user_id: &i64,
-) -> rusqlite::Result<()> {
+    user_id: i64,
+    key_version: i64,
+    algorithm: Algorithm,
+    wrapping_key: SecretKey,
+) -> Result<Session> {
+    let wrapped_keys: Vec<(i64, Vec<u8>)> = conn
+        .prepare("SELECT version, wrapped_key FROM user_keys WHERE user_id = ?1")?
+        .query_map(params![user_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut keys = BTreeMap::new();
+    for (version, wrapped) in wrapped_keys {
+        keys.insert(version, encryption::decrypt_bytes(&wrapped, &wrapping_key, &[])?);
+    }
+
+    let key = keys
+        .get(&key_version)
+        .cloned()
+        .ok_or_else(|| Error::Encoding(format!("keyring has no key version {}", key_version)))?;
+
+    let recovery_wrapped: Option<Vec<u8>> = conn.query_row(
+        "SELECT recovery_key_wrapped FROM users WHERE id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )?;
+    let recovery_key = recovery_wrapped
+        .map(|wrapped| encryption::decrypt_bytes(&wrapped, &wrapping_key, RECOVERY_KEY_AAD))
+        .transpose()?;
+
+    Ok(Session { user_id, key, key_version, algorithm, keys, wrapping_key, recovery_key })
+}
+
+// Moves a vault from an older key scheme onto a fresh keyring
+fn migrate_vault(
+    conn: &Connection,
+    user_id: i64,
+    password: &str,
+    params: &KdfParams,
+    algorithm: Algorithm,
+    old_key: &[u8],
+) -> Result<Session> {
+    let tx = conn.unchecked_transaction()?;
+    let session = create_keyring(&tx, user_id, password, params, algorithm)?;
+
+    let rows: Vec<(i64, Vec<u8>)> = tx
+        .prepare("SELECT id, password_encrypted FROM passwords WHERE user_id = ?1")?
+        .query_map(params![user_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    // Entries the old key does not open stay as they are, unreadable_entries reports them
+    for (id, encrypted) in rows {
+        if let Ok(plaintext) = encryption::decrypt(&encrypted, old_key, &[]) {
+            let aad = entry_aad(user_id, id, PASSWORD_FIELD);
+            tx.execute(
+                "UPDATE passwords SET password_encrypted = ?1, key_version = ?2 WHERE id = ?3",
+                params![encryption::encrypt(&plaintext, &session.key, session.algorithm, session.key_version as u32, &aad)?, session.key_version, id],
+            )?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(session)
+}
+
+// Rewrites entries encrypted before associated data was used so they are bound to their row
+fn bind_entries(conn: &Connection, session: &Session) -> Result<()> {
+    let bound: bool = conn.query_row(
+        "SELECT entries_bound FROM users WHERE id = ?1",
+        params![session.user_id],
+        |row| row.get(0),
+    )?;
+    if bound {
+        return Ok(());
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    let rows: Vec<(i64, Vec<u8>, i64)> = tx
+        .prepare("SELECT id, password_encrypted, key_version FROM passwords WHERE user_id = ?1")?
+        .query_map(params![session.user_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    for (id, encrypted, version) in rows {
+        let Some(key) = session.keys.get(&version) else { continue };
+
+        if let Ok(plaintext) = encryption::decrypt(&encrypted, key, &[]) {
+            let aad = entry_aad(session.user_id, id, PASSWORD_FIELD);
+            tx.execute(
+                "UPDATE passwords SET password_encrypted = ?1 WHERE id = ?2",
+                params![encryption::encrypt(&plaintext, key, session.algorithm, version as u32, &aad)?, id],
+            )?;
+        }
+    }
+
+    tx.execute("UPDATE users SET entries_bound = 1 WHERE id = ?1", params![session.user_id])?;
+    Ok(tx.commit()?)
+}
+
+// Encrypts account and username of entries stored before they were, adding the manifest key if missing
+fn encrypt_metadata(conn: &Connection, session: &mut Session) -> Result<()> {
+    let encrypted: bool = conn.query_row(
+        "SELECT metadata_encrypted FROM users WHERE id = ?1",
+        params![session.user_id],
+        |row| row.get(0),
+    )?;
+    if encrypted {
+        return Ok(());
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    if !session.keys.contains_key(&MANIFEST_KEY_VERSION) {
+        let manifest_key = encryption::generate_key();
+        store_key(&tx, session, MANIFEST_KEY_VERSION, &manifest_key)?;
+        session.keys.insert(MANIFEST_KEY_VERSION, manifest_key);
+    }
+
+    let rows: Vec<(i64, String, String, i64)> = tx
+        .prepare("SELECT id, account, username, key_version FROM passwords WHERE user_id = ?1 AND account_encrypted IS NULL")?
+        .query_map(params![session.user_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    for (id, account, username, version) in rows {
+        let Some(key) = session.keys.get(&version) else { continue };
+
+        tx.execute(
+            "UPDATE passwords SET account = '', username = '', account_encrypted = ?1, username_encrypted = ?2 WHERE id = ?3",
+            params![
+                encrypt_field(session, key, version, id, ACCOUNT_FIELD, &account)?,
+                encrypt_field(session, key, version, id, USERNAME_FIELD, &username)?,
+                id
+            ],
+        )?;
+    }
+
+    tx.execute("UPDATE users SET metadata_encrypted = 1 WHERE id = ?1", params![session.user_id])?;
+    Ok(tx.commit()?)
+}
+
+fn create_keyring(conn: &Connection, user_id: i64, password: &str, params: &KdfParams, algorithm: Algorithm) -> Result<Session> {
+    let key_version = 1;
+    let key = encryption::generate_key();
+    let keys = BTreeMap::from([(MANIFEST_KEY_VERSION, encryption::generate_key()), (key_version, key.clone())]);
+
+    let wrapping_key = wrap_keyring(conn, user_id, password, params, algorithm, &keys)?;
+    // Fresh keyrings only ever hold entries written with associated data
+    conn.execute(
+        "UPDATE users SET key_version = ?1, rotated_at = ?2, entries_bound = 1 WHERE id = ?3",
+        params![key_version, now(), user_id],
+    )?;
+
+    Ok(Session { user_id, key, key_version, algorithm, keys, wrapping_key, recovery_key: None })
+}
+
+// Wraps every key of the keyring under a key derived from the password with a fresh salt.
+// This is the only write needed when the master password or KDF settings change.
+fn wrap_keyring(
+    conn: &Connection,
+    user_id: i64,
+    password: &str,
+    params: &KdfParams,
+    algorithm: Algorithm,
+    keys: &BTreeMap<i64, SecretKey>,
+) -> Result<SecretKey> {
+    // The wrapping key needs its own salt, otherwise it would equal the output stored in password_hash
+    let key_salt = crypto::generate_salt();
+    let wrapping_key = crypto::derive_key_from_password(password, &key_salt, params)?;
+
+    conn.execute(
+        "UPDATE users SET key_salt = ?1, kdf_algorithm = ?2, kdf_m_cost = ?3, kdf_t_cost = ?4, kdf_p_cost = ?5 WHERE id = ?6",
+        params![
+            key_salt,
+            params.algorithm.as_str(),
+            params.m_cost,
+            params.t_cost,
+            params.p_cost,
+            user_id
+        ],
+    )?;
+    for (version, key) in keys {
+        conn.execute(
+            "INSERT INTO user_keys (user_id, version, wrapped_key, created_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (user_id, version) DO UPDATE SET wrapped_key = excluded.wrapped_key",
+            params![user_id, version, encryption::encrypt_bytes(key, &wrapping_key, algorithm, WRAPPING_KEY_VERSION, &[])?, now()],
+        )?;
+    }
+
+    Ok(wrapping_key)
+}
+
+// Adds a key to the keyring, wrapped by the password and, when the user has one, by the recovery key
+fn store_key(conn: &Connection, session: &Session, version: i64, key: &SecretKey) -> Result<()> {
+    let recovery_wrapped = session
+        .recovery_key
+        .as_ref()
+        .map(|recovery_key| encryption::encrypt_bytes(key, recovery_key, session.algorithm, WRAPPING_KEY_VERSION, &[]))
+        .transpose()?;
+
+    conn.execute(
+        "INSERT INTO user_keys (user_id, version, wrapped_key, recovery_wrapped_key, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            session.user_id,
+            version,
+            encryption::encrypt_bytes(key, &session.wrapping_key, session.algorithm, WRAPPING_KEY_VERSION, &[])?,
+            recovery_wrapped,
+            now()
+        ],
+    )?;
+    Ok(())
+}
+
+// Rehashes the password and re-wraps the keyring, with the key file if the user has one, with the given parameters
+fn set_master_password(conn: &Connection, session: &mut Session, password: &str, key_file: Option<&[u8]>, params: &KdfParams) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    write_master_password(&tx, session, password, key_file, params)?;
+    Ok(tx.commit()?)
+}
+
+// set_master_password for callers that already opened a transaction
+fn write_master_password(conn: &Connection, session: &mut Session, password: &str, key_file: Option<&[u8]>, params: &KdfParams) -> Result<()> {
+    let (hash, _salt) = crypto::hash_password(password, params)?;
+
     conn.execute(
-        "UPDATE passwords SET account = ?1, username = ?2, password_encrypted = ?3 WHERE account = ?4 AND username = ?5 AND user_id = ?6",
-        params![new_account, new_username, new_encrypted_password, old_account, old_username, user_id],
+        "UPDATE users SET password_hash = ?1, hash_includes_key_file = 0 WHERE id = ?2",
+        params![hash, session.user_id],
     )?;
+    let secret = unlock_secret(password, key_file);
+    session.wrapping_key = wrap_keyring(conn, session.user_id, &secret, params, session.algorithm, &session.keys)?;
+    // The recovery key is kept under the password too, so keys added by rotation can be wrapped by it
+    if let Some(recovery_key) = &session.recovery_key {
+        conn.execute(
+            "UPDATE users SET recovery_key_wrapped = ?1 WHERE id = ?2",
+            params![
+                encryption::encrypt_bytes(recovery_key, &session.wrapping_key, session.algorithm, WRAPPING_KEY_VERSION, RECOVERY_KEY_AAD)?,
+                session.user_id
+            ],
+        )?;
+    }
     Ok(())
 }
 
-pub fn login_user(conn: &Connection, username: &str, password: &str) -> Option<i64> {
-    let mut stmt = conn.prepare("SELECT id, password_hash FROM users WHERE username = ?1").ok()?;
-    let mut rows = stmt.query(params![username]).ok()?;
+pub fn register_user(conn: &Connection, username: &str, password: &str) -> Result<Session> {
+    register_user_with_params(conn, username, password, &crypto::calibrated_params(), Algorithm::preferred())
+}
+
+/// Registers a user whose vault is sealed with `algorithm` for as long as the account exists.
+pub fn register_user_with_params(
+    conn: &Connection,
+    username: &str,
+    password: &str,
+    params: &KdfParams,
+    algorithm: Algorithm,
+) -> Result<Session> {
+    let (hash, _salt) = crypto::hash_password(password, params)?;
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO users (username, password_hash, cipher, metadata_encrypted) VALUES (?1, ?2, ?3, 1)",
+        params![username, hash, algorithm.as_str()],
+    )
+    .map_err(|err| match err.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::ConstraintViolation) => Error::UsernameUnavailable,
+        _ => Error::Storage(err),
+    })?;
+    let session = create_keyring(&tx, tx.last_insert_rowid(), password, params, algorithm)?;
+    tx.commit()?;
+
+    Ok(session)
+}
+
+/// Replaces the master password of the user, returning the session unlocked with it.
+///
+/// Only the password hash and the wrapped keys change; entries stay encrypted as they are.
+/// Fails with `Error::Authentication` when `old_password` is wrong. Wrong guesses count towards
+/// the same throttling as failed logins, see `login_user_with_key_file`.
+pub fn change_master_password(
+    conn: &Connection,
+    user_id: i64,
+    old_password: &str,
+    new_password: &str,
+) -> Result<Session> {
+    change_master_password_with_key_file(conn, user_id, old_password, new_password, None)
+}
+
+/// `change_master_password` for accounts protected by a key file, which stays the same.
+pub fn change_master_password_with_key_file(
+    conn: &Connection,
+    user_id: i64,
+    old_password: &str,
+    new_password: &str,
+    key_file: Option<&[u8]>,
+) -> Result<Session> {
+    let mut session = unlock_user(conn, user_id, old_password, key_file)?;
+    let key_file = required_key_file(conn, user_id, key_file)?;
+    set_master_password(conn, &mut session, new_password, key_file, &crypto::calibrated_params())?;
+
+    Ok(session)
+}
+
+/// Deletes the user with every entry, key and code stored for them, once `master_password` unlocks the vault.
+pub fn delete_user(conn: &Connection, user_id: i64, master_password: &str) -> Result<()> {
+    delete_user_with_key_file(conn, user_id, master_password, None)
+}
 
-    if let Some(row) = rows.next().ok()? {
-        let user_id: i64 = row.get(0).ok()?;
-        let hash: String = row.get(1).ok()?;
+/// `delete_user` for accounts protected by a key file.
+pub fn delete_user_with_key_file(conn: &Connection, user_id: i64, master_password: &str, key_file: Option<&[u8]>) -> Result<()> {
+    unlock_user(conn, user_id, master_password, key_file)?;
 
-        if crypto::verify_password(&hash, password) {
-            return Some(user_id);
+    let username = username_of(conn, user_id)?;
+
+    let tx = conn.unchecked_transaction()?;
+    for attempts in [PASSWORD_ATTEMPTS, SECOND_FACTOR_ATTEMPTS] {
+        tx.execute("DELETE FROM login_attempts WHERE username_digest = ?1", params![attempts_digest(attempts, &username)])?;
+    }
+    // Entries, keys, recovery codes and the manifest follow through ON DELETE CASCADE
+    tx.execute("DELETE FROM users WHERE id = ?1", params![user_id])?;
+    tx.commit()?;
+
+    // Secure delete zeroed the freed pages, vacuuming drops them from the file
+    conn.execute_batch("VACUUM")?;
+    Ok(())
+}
+
+/// Makes `new_key_file` a second unlock factor next to the master password, or removes it when `None`.
+///
+/// The keyring is wrapped with both together, while the stored hash covers the password alone so
+/// logins can check it before asking for the file.
+/// Unlocks with `password` and `current_key_file` first and returns that session.
+pub fn set_key_file(
+    conn: &Connection,
+    user_id: i64,
+    password: &str,
+    current_key_file: Option<&[u8]>,
+    new_key_file: Option<&[u8]>,
+) -> Result<Session> {
+    let mut session = unlock_user(conn, user_id, password, current_key_file)?;
+
+    let tx = conn.unchecked_transaction()?;
+    write_master_password(&tx, &mut session, password, new_key_file, &crypto::calibrated_params())?;
+    tx.execute(
+        "UPDATE users SET key_file_required = ?1 WHERE id = ?2",
+        params![new_key_file.is_some(), user_id],
+    )?;
+    tx.commit()?;
+
+    Ok(session)
+}
+
+pub fn key_file_required(conn: &Connection, user_id: i64) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT key_file_required FROM users WHERE id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )?)
+}
+
+/// Creates a recovery key that unlocks the vault without the master password, replacing any earlier one.
+///
+/// Returns the key as the word list to show the user once; it is not stored in readable form.
+pub fn create_recovery_key(conn: &Connection, session: &mut Session) -> Result<SecretString> {
+    let recovery_key = encryption::generate_key();
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE users SET recovery_key_wrapped = ?1 WHERE id = ?2",
+        params![
+            encryption::encrypt_bytes(&recovery_key, &session.wrapping_key, session.algorithm, WRAPPING_KEY_VERSION, RECOVERY_KEY_AAD)?,
+            session.user_id
+        ],
+    )?;
+    for (version, key) in &session.keys {
+        tx.execute(
+            "UPDATE user_keys SET recovery_wrapped_key = ?1 WHERE user_id = ?2 AND version = ?3",
+            params![
+                encryption::encrypt_bytes(key, &recovery_key, session.algorithm, WRAPPING_KEY_VERSION, &[])?,
+                session.user_id,
+                version
+            ],
+        )?;
+    }
+    tx.commit()?;
+
+    let words = crypto::recovery_words(&recovery_key)?;
+    session.recovery_key = Some(recovery_key);
+    Ok(words)
+}
+
+pub fn has_recovery_key(conn: &Connection, user_id: i64) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT recovery_key_wrapped IS NOT NULL FROM users WHERE id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )?)
+}
+
+/// Unlocks the vault of a user who forgot the master password with the words of their recovery key,
+/// setting `new_password` as the master password. The recovery key stays valid.
+/// A key file the account had is no longer needed afterwards, as it may be lost along with the password.
+///
+/// Fails with `Error::Authentication` when the user has no recovery key or the words do not match it.
+pub fn recover_account(conn: &Connection, username: &str, recovery_words: &str, new_password: &str) -> Result<Session> {
+    let recovery_key = crypto::parse_recovery_words(recovery_words).map_err(|_| Error::Authentication)?;
+    let (user_id, key_version, cipher): (i64, Option<i64>, String) = conn
+        .query_row(
+            "SELECT id, key_version, cipher FROM users WHERE username = ?1 AND recovery_key_wrapped IS NOT NULL",
+            params![username],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?
+        .ok_or(Error::Authentication)?;
+    let key_version = key_version.ok_or(Error::Authentication)?;
+
+    let wrapped_keys: Vec<(i64, Option<Vec<u8>>)> = conn
+        .prepare("SELECT version, recovery_wrapped_key FROM user_keys WHERE user_id = ?1")?
+        .query_map(params![user_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut keys = BTreeMap::new();
+    for (version, wrapped) in wrapped_keys {
+        let wrapped = wrapped.ok_or_else(|| Error::Encoding(format!("key version {} is not wrapped by the recovery key", version)))?;
+        let key = encryption::decrypt_bytes(&wrapped, &recovery_key, &[]).map_err(|_| Error::Authentication)?;
+        keys.insert(version, key);
+    }
+    let key = keys
+        .get(&key_version)
+        .cloned()
+        .ok_or_else(|| Error::Encoding(format!("keyring has no key version {}", key_version)))?;
+
+    let mut session = Session {
+        user_id,
+        key,
+        key_version,
+        algorithm: cipher.parse()?,
+        keys,
+        // Replaced below by the key derived from the new password
+        wrapping_key: SecretKey::new(Vec::new()),
+        recovery_key: Some(recovery_key),
+    };
+    let tx = conn.unchecked_transaction()?;
+    write_master_password(&tx, &mut session, new_password, None, &crypto::calibrated_params())?;
+    tx.execute("UPDATE users SET key_file_required = 0 WHERE id = ?1", params![user_id])?;
+    tx.commit()?;
+
+    Ok(session)
+}
+
+/// Re-encrypts every entry of the user with a new key version.
+///
+/// The new key is stored before any entry is touched, so a rotation interrupted
+/// halfway is picked up again with the same key on the next call.
+pub fn rotate_keys(conn: &Connection, session: &mut Session) -> Result<()> {
+    let target_version = match session.keys.keys().next_back() {
+        Some(&version) if version > session.key_version => version,
+        latest => {
+            let version = latest.copied().unwrap_or(0) + 1;
+            let key = encryption::generate_key();
+            store_key(conn, session, version, &key)?;
+            session.keys.insert(version, key);
+            version
+        }
+    };
+    let target_key = session.keys[&target_version].clone();
+
+    let tx = conn.unchecked_transaction()?;
+    let rows: Vec<StoredEntry> = tx
+        .prepare(&format!("SELECT {} FROM passwords WHERE user_id = ?1 AND key_version != ?2", StoredEntry::COLUMNS))?
+        .query_map(params![session.user_id, target_version], StoredEntry::from_row)?
+        .collect::<rusqlite::Result<_>>()?;
+
+    for entry in rows {
+        let fields = [(ACCOUNT_FIELD, &entry.account), (USERNAME_FIELD, &entry.username), (PASSWORD_FIELD, &entry.password)];
+        let reencrypted = fields
+            .iter()
+            .map(|(field, encrypted)| {
+                let plaintext = decrypt_field(session, encrypted, entry.key_version, entry.id, field)?;
+                encrypt_field(session, &target_key, target_version, entry.id, field, &plaintext)
+            })
+            .collect::<Result<Vec<_>>>();
+        let optional = [(OTP_FIELD, &entry.otp), (ATTRIBUTES_FIELD, &entry.attributes)];
+        let reencrypted_optional = optional
+            .iter()
+            .map(|(field, encrypted)| {
+                encrypted
+                    .as_ref()
+                    .map(|encrypted| {
+                        let plaintext = decrypt_field(session, encrypted, entry.key_version, entry.id, field)?;
+                        encrypt_field(session, &target_key, target_version, entry.id, field, &plaintext)
+                    })
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>>>();
+
+        // Entries that no longer decrypt are left as they are
+        if let (Ok(reencrypted), Ok(optional)) = (reencrypted, reencrypted_optional) {
+            tx.execute(
+                "UPDATE passwords SET account_encrypted = ?1, username_encrypted = ?2, password_encrypted = ?3, otp_encrypted = ?4,
+                 attributes_encrypted = ?5, key_version = ?6 WHERE id = ?7",
+                params![reencrypted[0], reencrypted[1], reencrypted[2], optional[0], optional[1], target_version, entry.id],
+            )?;
         }
     }
-    None
+
+    tx.execute(
+        "UPDATE users SET key_version = ?1, rotated_at = ?2 WHERE id = ?3",
+        params![target_version, now(), session.user_id],
+    )?;
+    update_manifest(&tx, session, 0)?;
+    tx.commit()?;
+
+    session.key = target_key;
+    session.key_version = target_version;
+    Ok(())
 }
 
-pub fn register_user(conn: &Connection, username: &str, password: &str) -> rusqlite::Result<()> {
-    let (hash, _salt) = crypto::hash_password(password);
-    let hashed = crypto::derive_key_from_password(password, stringify!((_salt)));
+pub fn rotation_due(conn: &Connection, user_id: i64) -> Result<bool> {
+    let (rotated_at, interval_days): (Option<i64>, i64) = conn.query_row(
+        "SELECT rotated_at, rotation_interval_days FROM users WHERE id = ?1",
+        params![user_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    if interval_days <= 0 {
+        return Ok(false);
+    }
+
+    Ok(rotated_at.is_none_or(|rotated_at| now() - rotated_at >= interval_days * 24 * 60 * 60))
+}
+
+pub fn get_rotation_interval(conn: &Connection, user_id: i64) -> Result<i64> {
+    Ok(conn.query_row(
+        "SELECT rotation_interval_days FROM users WHERE id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )?)
+}
+
+pub fn set_rotation_interval(conn: &Connection, user_id: i64, days: i64) -> Result<()> {
     conn.execute(
-        "INSERT INTO users (username, password_hash) VALUES (?1, ?2)",
-        params![username, hash],
+        "UPDATE users SET rotation_interval_days = ?1 WHERE id = ?2",
+        params![days, user_id],
+    )?;
+    Ok(())
+}
+
+pub fn totp_enabled(conn: &Connection, user_id: i64) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT totp_secret IS NOT NULL FROM users WHERE id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )?)
+}
+
+/// Turns on two-factor login once `code` proves the authenticator holds `secret`.
+/// Fails with `Error::Authentication` when the code does not match.
+pub fn enable_totp(conn: &Connection, session: &Session, secret: &[u8], code: &str) -> Result<()> {
+    let step = totp::matching_step(secret, code, totp::unix_time()).ok_or(Error::Authentication)?;
+    // Users have no entry id, 0 never names a row of passwords
+    let aad = entry_aad(session.user_id, 0, TOTP_FIELD);
+    let encrypted = encryption::encrypt_bytes(secret, &session.key, session.algorithm, session.key_version as u32, &aad)?;
+
+    conn.execute(
+        "UPDATE users SET totp_secret = ?1, totp_last_step = ?2, totp_reenrollment = 0 WHERE id = ?3",
+        params![encrypted, step as i64, session.user_id],
+    )?;
+    Ok(())
+}
+
+/// Turns off two-factor login once `code` from the authenticator checks out, as `verify_totp` would.
+/// Users who lost the authenticator fall back on `use_recovery_code` instead.
+pub fn disable_totp(conn: &Connection, session: &Session, code: &str) -> Result<()> {
+    verify_totp(conn, session, code)?;
+    conn.execute(
+        "UPDATE users SET totp_secret = NULL, totp_last_step = NULL WHERE id = ?1",
+        params![session.user_id],
+    )?;
+    Ok(())
+}
+
+/// Checks the authentication code asked for after `login_user`, allowing `totp::SKEW` periods of clock drift.
+///
+/// A code is accepted once; replaying it, or any code of an earlier period, fails with `Error::Authentication`.
+/// Wrong codes are throttled like wrong passwords, counted apart so logging in again does not reset them,
+/// and `Error::Throttled` is returned without checking the code while the user has to wait.
+pub fn verify_totp(conn: &Connection, session: &Session, code: &str) -> Result<()> {
+    let username = username_of(conn, session.user_id)?;
+    throttled(conn, SECOND_FACTOR_ATTEMPTS, &username, || check_totp(conn, session, code))
+}
+
+fn check_totp(conn: &Connection, session: &Session, code: &str) -> Result<()> {
+    let encrypted: Vec<u8> = conn
+        .query_row("SELECT totp_secret FROM users WHERE id = ?1", params![session.user_id], |row| row.get(0))
+        .optional()?
+        .flatten()
+        .ok_or(Error::Authentication)?;
+    let key = entry_key(session, &encrypted, session.key_version).ok_or(Error::Aead)?;
+    let secret = encryption::decrypt_bytes(&encrypted, key, &entry_aad(session.user_id, 0, TOTP_FIELD))?;
+
+    let step = totp::matching_step(&secret, code, totp::unix_time()).ok_or(Error::Authentication)?;
+    // Compare-and-set so two logins racing with the same code cannot both succeed
+    let updated = conn.execute(
+        "UPDATE users SET totp_last_step = ?1 WHERE id = ?2 AND (totp_last_step IS NULL OR totp_last_step < ?1)",
+        params![step as i64, session.user_id],
     )?;
+
+    if updated == 0 {
+        return Err(Error::Authentication);
+    }
     Ok(())
 }
 
+/// Replaces every recovery code of the user with `RECOVERY_CODE_COUNT` new ones.
+///
+/// Only hashes are stored, the returned codes cannot be shown again.
+pub fn generate_recovery_codes(conn: &Connection, session: &Session) -> Result<Vec<SecretString>> {
+    let codes: Vec<SecretString> = (0..RECOVERY_CODE_COUNT).map(|_| crypto::generate_recovery_code()).collect();
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM recovery_codes WHERE user_id = ?1", params![session.user_id])?;
+    for code in &codes {
+        let (hash, _salt) = crypto::hash_password(&crypto::normalize_recovery_code(code), &RECOVERY_CODE_PARAMS)?;
+        tx.execute(
+            "INSERT INTO recovery_codes (user_id, code_hash, created_at) VALUES (?1, ?2, ?3)",
+            params![session.user_id, hash, now()],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(codes)
+}
+
+pub fn recovery_codes_left(conn: &Connection, user_id: i64) -> Result<i64> {
+    Ok(conn.query_row(
+        "SELECT COUNT(*) FROM recovery_codes WHERE user_id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )?)
+}
+
+/// Accepts a recovery code in place of the authentication code after `login_user`.
+///
+/// Recovery codes stand in for a lost authenticator only, the master password is still needed
+/// to log in; a forgotten one is what the recovery key of `recover_account` is for.
+/// The code is consumed, the lost authenticator stops working and the user has to enroll
+/// a new one before using the vault, see `totp_reenrollment_required`.
+/// Fails with `Error::Authentication` when no unused code matches. Wrong codes count towards the
+/// same throttling as wrong authentication codes of `verify_totp`.
+pub fn use_recovery_code(conn: &Connection, session: &Session, code: &str) -> Result<()> {
+    let username = username_of(conn, session.user_id)?;
+    throttled(conn, SECOND_FACTOR_ATTEMPTS, &username, || spend_recovery_code(conn, session, code))
+}
+
+fn spend_recovery_code(conn: &Connection, session: &Session, code: &str) -> Result<()> {
+    let code = crypto::normalize_recovery_code(code);
+    let hashes: Vec<(i64, String)> = conn
+        .prepare("SELECT id, code_hash FROM recovery_codes WHERE user_id = ?1")?
+        .query_map(params![session.user_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut matched = None;
+    for (id, hash) in hashes {
+        if crypto::verify_password(&hash, &code)? {
+            matched = Some(id);
+            break;
+        }
+    }
+    let code_id = matched.ok_or(Error::Authentication)?;
+
+    let tx = conn.unchecked_transaction()?;
+    // Whoever deletes the row first gets to use the code
+    if tx.execute("DELETE FROM recovery_codes WHERE id = ?1", params![code_id])? == 0 {
+        return Err(Error::Authentication);
+    }
+    tx.execute(
+        "UPDATE users SET totp_reenrollment = 1, totp_secret = NULL, totp_last_step = NULL WHERE id = ?1",
+        params![session.user_id],
+    )?;
+    Ok(tx.commit()?)
+}
+
+/// Whether the user logged in with a recovery code and has not enrolled a new authenticator since.
+pub fn totp_reenrollment_required(conn: &Connection, user_id: i64) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT totp_reenrollment FROM users WHERE id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )?)
+}
+
 pub fn get_user_id(conn: &Connection, username: &str) -> Result<i64> {
-    conn.query_row(
+    Ok(conn.query_row(
         "SELECT id FROM users WHERE username = ?1",
         params![username],
         |row| row.get(0),
-    )
+    )?)
 }
\ No newline at end of file
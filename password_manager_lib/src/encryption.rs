@@ -1,38 +1,232 @@
+use std::str::FromStr;
 use aes_gcm::{
     Aes256Gcm,
-    aead::{Aead, KeyInit, OsRng, generic_array::GenericArray, rand_core::RngCore},
+    aead::{Aead, KeyInit, OsRng, Payload, rand_core::RngCore},
     Nonce,
 };
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use crate::secret::{SecretKey, SecretString};
+use crate::{Error, Result};
 
-pub fn encrypt(plaintext: &str, key: &[u8]) -> Vec<u8> {
-    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
+const MAGIC: &[u8; 4] = b"PMv\0";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 4;
 
-    let mut nonce = [0u8; 12];
+/// AEAD used to seal vault data. Implementations produce `nonce || ciphertext`.
+pub trait VaultCipher {
+    fn seal(&self, plaintext: &[u8], key: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
 
-    OsRng.fill_bytes(&mut nonce);
+    fn open(&self, data: &[u8], key: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub struct Aes256GcmCipher;
+
+impl VaultCipher for Aes256GcmCipher {
+    fn seal(&self, plaintext: &[u8], key: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| Error::Aead)?;
+
+        let mut nonce = [0u8; 12];
+
+        OsRng.fill_bytes(&mut nonce);
+
+        let nonce_array = Nonce::from_slice(&nonce);
+
+        let ciphertext = cipher.encrypt(nonce_array, Payload { msg: plaintext, aad }).map_err(|_| Error::Aead)?;
+
+        let mut result = nonce.to_vec();
 
-    let nonce_array = Nonce::from_slice(&nonce);
+        result.extend(ciphertext);
+        Ok(result)
+    }
+
+    fn open(&self, data: &[u8], key: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < 12 {
+            return Err(Error::Encoding("ciphertext too short".to_string()));
+        }
 
-    let ciphertext = cipher.encrypt(nonce_array, plaintext.as_bytes()).unwrap();
+        let (nonce_bytes, ciphertext) = data.split_at(12);
 
-    let mut result = nonce.to_vec();
+        // A key of the wrong length cannot be the right one
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| Error::Aead)?;
 
-    result.extend(ciphertext);
-    result
+        let nonce = Nonce::from_slice(nonce_bytes);
+
+        cipher.decrypt(nonce, Payload { msg: ciphertext, aad }).map_err(|_| Error::Aead)
+    }
 }
 
-pub fn decrypt(data: &[u8], key: &[u8]) -> Result<String, &'static str> {
-    if data.len() < 12 {
-        return Err("Data too short");
+/// 192-bit random nonces, so there is no practical limit on how many entries one key seals.
+pub struct XChaCha20Poly1305Cipher;
+
+impl VaultCipher for XChaCha20Poly1305Cipher {
+    fn seal(&self, plaintext: &[u8], key: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new_from_slice(key).map_err(|_| Error::Aead)?;
+
+        let mut nonce = [0u8; 24];
+
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = cipher.encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad }).map_err(|_| Error::Aead)?;
+
+        let mut result = nonce.to_vec();
+
+        result.extend(ciphertext);
+        Ok(result)
+    }
+
+    fn open(&self, data: &[u8], key: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < 24 {
+            return Err(Error::Encoding("ciphertext too short".to_string()));
+        }
+
+        let (nonce_bytes, ciphertext) = data.split_at(24);
+
+        let cipher = XChaCha20Poly1305::new_from_slice(key).map_err(|_| Error::Aead)?;
+
+        cipher.decrypt(XNonce::from_slice(nonce_bytes), Payload { msg: ciphertext, aad }).map_err(|_| Error::Aead)
     }
+}
 
-    let (nonce_bytes, ciphertext) = data.split_at(12);
+/// Cipher a blob was produced with, as recorded in its header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Aes256Gcm,
+    XChaCha20Poly1305,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 2] = [Algorithm::Aes256Gcm, Algorithm::XChaCha20Poly1305];
+
+    /// AES-256-GCM where the CPU accelerates AES, XChaCha20-Poly1305 everywhere else.
+    pub fn preferred() -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if std::arch::is_x86_feature_detected!("aes") {
+            return Algorithm::Aes256Gcm;
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("aes") {
+            return Algorithm::Aes256Gcm;
+        }
+        Algorithm::XChaCha20Poly1305
+    }
 
-    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
+    pub fn cipher(self) -> &'static dyn VaultCipher {
+        match self {
+            Algorithm::Aes256Gcm => &Aes256GcmCipher,
+            Algorithm::XChaCha20Poly1305 => &XChaCha20Poly1305Cipher,
+        }
+    }
 
-    let nonce = Nonce::from_slice(nonce_bytes);
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Algorithm::Aes256Gcm => "aes-256-gcm",
+            Algorithm::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
 
-    let decrypted = cipher.decrypt(nonce, ciphertext).map_err(|_| "Decryption failed")?;
+    fn id(self) -> u8 {
+        match self {
+            Algorithm::Aes256Gcm => 1,
+            Algorithm::XChaCha20Poly1305 => 2,
+        }
+    }
 
-    String::from_utf8(decrypted).map_err(|_| "Invalid UTF-8")
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Algorithm::Aes256Gcm),
+            2 => Some(Algorithm::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Algorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.as_str() == s)
+            .ok_or_else(|| Error::Encoding(format!("unknown cipher {}", s)))
+    }
+}
+
+/// Header in front of every ciphertext: magic, format version, algorithm id and key version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub format_version: u8,
+    pub algorithm: Algorithm,
+    pub key_version: u32,
+}
+
+impl Header {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.format_version);
+        bytes.push(self.algorithm.id());
+        bytes.extend(self.key_version.to_be_bytes());
+        bytes
+    }
+}
+
+/// Reads the header of a blob. Returns `None` for blobs written before headers existed.
+pub fn read_header(data: &[u8]) -> Option<Header> {
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+
+    let format_version = data[MAGIC.len()];
+    if format_version != FORMAT_VERSION {
+        return None;
+    }
+
+    Some(Header {
+        format_version,
+        algorithm: Algorithm::from_id(data[MAGIC.len() + 1])?,
+        key_version: u32::from_be_bytes(data[MAGIC.len() + 2..HEADER_LEN].try_into().ok()?),
+    })
+}
+
+pub fn generate_key() -> SecretKey {
+    let mut key = vec![0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    SecretKey::new(key)
+}
+
+/// `aad` is authenticated but not stored; decryption only succeeds with the same value.
+/// `algorithm` and `key_version` are recorded in the header so readers know how to open the blob.
+pub fn encrypt(plaintext: &str, key: &[u8], algorithm: Algorithm, key_version: u32, aad: &[u8]) -> Result<Vec<u8>> {
+    encrypt_bytes(plaintext.as_bytes(), key, algorithm, key_version, aad)
+}
+
+pub fn encrypt_bytes(plaintext: &[u8], key: &[u8], algorithm: Algorithm, key_version: u32, aad: &[u8]) -> Result<Vec<u8>> {
+    let header = Header { format_version: FORMAT_VERSION, algorithm, key_version }.to_bytes();
+
+    // The header is authenticated along with the caller's associated data
+    let mut result = header.clone();
+    result.extend(algorithm.cipher().seal(plaintext, key, &[header.as_slice(), aad].concat())?);
+    Ok(result)
+}
+
+pub fn decrypt(data: &[u8], key: &[u8], aad: &[u8]) -> Result<SecretString> {
+    let decrypted = decrypt_bytes(data, key, aad)?;
+
+    std::str::from_utf8(&decrypted)
+        .map(SecretString::from)
+        .map_err(|_| Error::Encoding("plaintext is not UTF-8".to_string()))
+}
+
+pub fn decrypt_bytes(data: &[u8], key: &[u8], aad: &[u8]) -> Result<SecretKey> {
+    let decrypted = read_header(data).and_then(|header| {
+        let (header_bytes, body) = data.split_at(HEADER_LEN);
+        let aad = [header_bytes, aad].concat();
+
+        header.algorithm.cipher().open(body, key, &aad).ok()
+    });
+
+    // Headerless blobs are AES-256-GCM and start with a random nonce, which can happen to look like a header
+    match decrypted {
+        Some(plaintext) => Ok(SecretKey::new(plaintext)),
+        None => Aes256GcmCipher.open(data, key, aad).map(SecretKey::new),
+    }
 }
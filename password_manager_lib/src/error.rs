@@ -0,0 +1,101 @@
+use std::fmt;
+
+/// Everything that can go wrong in the library.
+#[derive(Debug)]
+pub enum Error {
+    /// Argon2 rejected its parameters or failed to hash.
+    Kdf(String),
+    /// A ciphertext did not authenticate: wrong key, wrong associated data or tampering.
+    Aead,
+    /// Stored data is malformed, e.g. a password hash, salt or ciphertext that cannot be parsed.
+    Encoding(String),
+    /// The database failed.
+    Storage(rusqlite::Error),
+    /// The credentials given do not unlock the account.
+    Authentication,
+    /// The account is protected by a key file and none was given.
+    KeyFileRequired,
+    /// Too many failed logins; the account accepts the next attempt after this many seconds.
+    Throttled(u64),
+    /// Registration picked a username that is already in use.
+    UsernameUnavailable,
+    /// The store of remembered device keys failed.
+    KeyStore(String),
+    /// The D-Bus connection of the Secret Service provider failed.
+    Bus(String),
+    /// The vault does not match its manifest: entries were removed, altered or rolled back outside the library.
+    Tampered(String),
+    /// The database was written by a newer version of the library; holds the schema version it has.
+    NewerSchema(i64),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Kdf(message) => write!(f, "Key derivation failed: {}", message),
+            Error::Aead => write!(f, "Decryption failed: data is corrupted or was tampered with"),
+            Error::Encoding(message) => write!(f, "Malformed data: {}", message),
+            Error::Storage(err) => write!(f, "Database error: {}", err),
+            Error::Authentication => write!(f, "Invalid username or password"),
+            Error::KeyFileRequired => write!(f, "This account needs its key file to unlock"),
+            Error::Throttled(seconds) => write!(f, "Too many failed attempts, try again in {} s", seconds),
+            Error::UsernameUnavailable => write!(f, "Username is not available"),
+            Error::KeyStore(message) => write!(f, "Key store failed: {}", message),
+            Error::Bus(message) => write!(f, "D-Bus failed: {}", message),
+            Error::Tampered(message) => write!(f, "Vault integrity check failed: {}", message),
+            Error::NewerSchema(version) => write!(
+                f,
+                "Database schema version {} is newer than the supported {}, update the application",
+                version,
+                crate::database::SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Storage(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::KeyStore(err.to_string())
+    }
+}
+
+impl From<secret_service::Error> for Error {
+    fn from(err: secret_service::Error) -> Self {
+        Error::KeyStore(err.to_string())
+    }
+}
+
+impl From<zbus::Error> for Error {
+    fn from(err: zbus::Error) -> Self {
+        Error::Bus(err.to_string())
+    }
+}
+
+impl From<argon2::Error> for Error {
+    fn from(err: argon2::Error) -> Self {
+        Error::Kdf(err.to_string())
+    }
+}
+
+impl From<argon2::password_hash::Error> for Error {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        Error::Kdf(err.to_string())
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use secret_service::EncryptionType;
+use secret_service::blocking::SecretService;
+use sha2::{Digest, Sha256};
+use crate::encryption::{self, Algorithm};
+use crate::secret::SecretKey;
+use crate::Result;
+
+/// Somewhere a device keeps the key that unlocks a user's vault, so it opens without the master password.
+pub trait KeyStore {
+    /// Saves `key` for `username`, replacing the one saved before.
+    fn save(&self, username: &str, key: &SecretKey) -> Result<()>;
+    /// The key saved for `username`, or `None` if this device does not remember the user.
+    fn load(&self, username: &str) -> Result<Option<SecretKey>>;
+    /// Forgets the key of `username`. Forgetting a user that was never saved is not an error.
+    fn delete(&self, username: &str) -> Result<()>;
+}
+
+const DEVICE_KEY_FILE: &str = "device.key";
+
+/// Keys sealed into files of one directory under a random device key kept next to them.
+///
+/// The files are only readable by their owner, but anyone who can read the directory can unlock the
+/// remembered vaults. Prefer `SecretServiceKeyStore` where a desktop keyring runs.
+pub struct FileKeyStore {
+    dir: PathBuf,
+}
+
+impl FileKeyStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileKeyStore { dir: dir.into() }
+    }
+
+    fn device_key(&self) -> Result<SecretKey> {
+        let path = self.dir.join(DEVICE_KEY_FILE);
+        match fs::read(&path) {
+            Ok(key) => Ok(SecretKey::new(key)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir_all(&self.dir)?;
+                let key = encryption::generate_key();
+                write_private(&path, &key)?;
+                Ok(key)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Usernames are hashed so the directory listing does not reveal who is remembered
+    fn entry_path(&self, username: &str) -> PathBuf {
+        let mut name = String::with_capacity(64 + 4);
+        for byte in Sha256::digest(username.as_bytes()) {
+            name.push_str(&format!("{:02x}", byte));
+        }
+        name.push_str(".key");
+        self.dir.join(name)
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn save(&self, username: &str, key: &SecretKey) -> Result<()> {
+        let device_key = self.device_key()?;
+        let sealed = encryption::encrypt_bytes(key, &device_key, Algorithm::preferred(), 0, username.as_bytes())?;
+        write_private(&self.entry_path(username), &sealed)
+    }
+
+    fn load(&self, username: &str) -> Result<Option<SecretKey>> {
+        let sealed = match fs::read(self.entry_path(username)) {
+            Ok(sealed) => sealed,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let device_key = self.device_key()?;
+        Ok(Some(encryption::decrypt_bytes(&sealed, &device_key, username.as_bytes())?))
+    }
+
+    fn delete(&self, username: &str) -> Result<()> {
+        match fs::remove_file(self.entry_path(username)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Replaces `path` with a file only its owner can read.
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    Ok(file.sync_all()?)
+}
+
+const APPLICATION: &str = "password_manager";
+
+/// Keys kept as items of the default collection of the desktop keyring (org.freedesktop.secrets).
+pub struct SecretServiceKeyStore {
+    service: SecretService<'static>,
+}
+
+impl SecretServiceKeyStore {
+    /// Fails when no Secret Service provider runs on the session bus.
+    pub fn connect() -> Result<Self> {
+        Ok(SecretServiceKeyStore { service: SecretService::connect(EncryptionType::Dh)? })
+    }
+
+    fn attributes(username: &str) -> HashMap<&str, &str> {
+        HashMap::from([("application", APPLICATION), ("username", username)])
+    }
+}
+
+impl KeyStore for SecretServiceKeyStore {
+    fn save(&self, username: &str, key: &SecretKey) -> Result<()> {
+        let collection = self.service.get_default_collection()?;
+        collection.ensure_unlocked()?;
+        let label = format!("Password manager key of {}", username);
+        collection.create_item(&label, Self::attributes(username), key, true, "application/octet-stream")?;
+        Ok(())
+    }
+
+    fn load(&self, username: &str) -> Result<Option<SecretKey>> {
+        let found = self.service.search_items(Self::attributes(username))?;
+        let Some(item) = found.unlocked.first().or(found.locked.first()) else {
+            return Ok(None);
+        };
+        item.ensure_unlocked()?;
+        Ok(Some(SecretKey::new(item.get_secret()?)))
+    }
+
+    fn delete(&self, username: &str) -> Result<()> {
+        let found = self.service.search_items(Self::attributes(username))?;
+        for item in found.unlocked.iter().chain(&found.locked) {
+            item.delete()?;
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,56 @@
 pub mod crypto;
 pub mod encryption;
 pub mod database;
+pub mod secret;
+pub mod totp;
+pub mod keystore;
+pub mod provider;
+mod error;
+
+pub use error::{Error, Result};
 
 #[cfg(test)]
 mod tests {
     use super::crypto::*;
     use super::encryption::*;
+    use super::database::*;
+    use super::secret::*;
+    use super::totp;
+    use super::keystore::*;
+    use super::provider::{self, Provider, UnlockedVaults};
+    use super::Error;
+
+    // Cheap key derivation, so only the tests about its cost pay for the real one
+    fn register(conn: &rusqlite::Connection, username: &str, password: &str) -> super::Result<Session> {
+        register_user_with_params(conn, username, password, &KdfParams::LEGACY, Algorithm::preferred())
+    }
+
+    // Copies of the database at `path` that an upgrade from `version` left behind
+    fn backups(path: &str, version: i64) -> Vec<String> {
+        let prefix = format!("{}.v{}.", path, version);
+        std::fs::read_dir(std::env::temp_dir()).unwrap()
+            .map(|entry| entry.unwrap().path().to_str().unwrap().to_string())
+            .filter(|name| name.starts_with(&prefix) && name.ends_with(".bak"))
+            .collect()
+    }
+
+    // nonce || ciphertext under AES-256-GCM, as written before ciphertexts carried a header
+    fn headerless(plaintext: &str, key: &[u8]) -> Vec<u8> {
+        use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce};
+
+        let nonce = [1u8; 12];
+        let mut legacy = nonce.to_vec();
+        legacy.extend(Aes256Gcm::new_from_slice(key).unwrap().encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes()).unwrap());
+        legacy
+    }
 
     #[test]
     fn test_password_hashing() {
         let password = "DocentoveHeslo";
 
-        let (hash, _) = hash_password(password);
+        let (hash, _) = hash_password(password, &KdfParams::default()).unwrap();
 
-        assert!(verify_password(&hash, password));
+        assert!(verify_password(&hash, password).unwrap());
     }
 
     #[test]
@@ -22,10 +59,955 @@ mod tests {
 
         let plaintext = "DocentoveHeslo2";
 
-        let encrypted = encrypt(plaintext, &key);
+        let encrypted = encrypt(plaintext, &key, Algorithm::Aes256Gcm, 1, b"1:1:password_encrypted").unwrap();
+
+        let decrypted = decrypt(&encrypted, &key, b"1:1:password_encrypted").unwrap();
+
+        assert_eq!(plaintext, decrypted.as_str());
+        assert!(decrypt(&encrypted, &key, b"1:2:password_encrypted").is_err());
+    }
+
+    #[test]
+    fn test_cipher_backends_round_trip() {
+        let key = generate_key();
+
+        for algorithm in Algorithm::ALL {
+            let encrypted = encrypt("DocentoveHeslo2", &key, algorithm, 1, b"aad").unwrap();
+
+            assert_eq!(read_header(&encrypted).unwrap().algorithm, algorithm);
+            assert_eq!(decrypt(&encrypted, &key, b"aad").unwrap().as_str(), "DocentoveHeslo2");
+            assert!(decrypt(&encrypted, &key, b"other").is_err());
+
+            let sealed = algorithm.cipher().seal(b"DocentoveHeslo2", &key, b"").unwrap();
+            assert_eq!(algorithm.cipher().open(&sealed, &key, b"").unwrap(), b"DocentoveHeslo2");
+            assert!(matches!(algorithm.cipher().seal(b"DocentoveHeslo2", &key[..16], b""), Err(Error::Aead)));
+            assert!(matches!(algorithm.cipher().open(&sealed, &key[..16], b""), Err(Error::Aead)));
+        }
+    }
+
+    #[test]
+    fn test_cipher_backends_not_interchangeable() {
+        let key = generate_key();
+
+        let aes = Aes256GcmCipher.seal(b"Tajne", &key, b"").unwrap();
+        let xchacha = XChaCha20Poly1305Cipher.seal(b"Tajne", &key, b"").unwrap();
+
+        assert!(XChaCha20Poly1305Cipher.open(&aes, &key, b"").is_err());
+        assert!(Aes256GcmCipher.open(&xchacha, &key, b"").is_err());
+    }
+
+    #[test]
+    fn test_ciphertext_header() {
+        let key = [0u8; 32];
+
+        let encrypted = encrypt("DocentoveHeslo2", &key, Algorithm::Aes256Gcm, 7, b"").unwrap();
+        let header = read_header(&encrypted).unwrap();
+
+        assert_eq!(header.algorithm, Algorithm::Aes256Gcm);
+        assert_eq!(header.key_version, 7);
+
+        let mut tampered = encrypted.clone();
+        tampered[9] ^= 1;
+        assert!(decrypt(&tampered, &key, b"").is_err());
+    }
+
+    #[test]
+    fn test_headerless_ciphertext_still_decrypts() {
+        let key = [0u8; 32];
+        let legacy = headerless("Tajne", &key);
+
+        assert!(read_header(&legacy).is_none());
+        assert_eq!(decrypt(&legacy, &key, b"").unwrap().as_str(), "Tajne");
+    }
+
+    #[test]
+    fn test_secrets_redacted_in_debug() {
+        let password = SecretString::from("DocentoveHeslo");
+        let key = SecretKey::new(vec![42u8; 32]);
+
+        assert!(!format!("{:?}", password).contains("Docent"));
+        assert!(!format!("{:?}", key).contains("42"));
+        assert_eq!(password.as_str(), "DocentoveHeslo");
+    }
+
+    #[test]
+    fn test_login_derives_registered_key() {
+        let conn = initialize_db(":memory:").unwrap();
+
+        let registered = register_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let session = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+
+        assert_eq!(registered.user_id, session.user_id);
+        assert_eq!(registered.key, session.key);
+        assert_ne!(&session.key[..], &[42u8; 32]);
+        assert!(login_user(&conn, "docent", "ZleHeslo").is_err());
+    }
+
+    #[test]
+    fn test_legacy_vault_migrated_on_login() {
+        let conn = initialize_db(":memory:").unwrap();
+        let legacy_key = [42u8; 32];
+
+        let (hash, _) = hash_password("DocentoveHeslo", &KdfParams::LEGACY).unwrap();
+        conn.execute("INSERT INTO users (username, password_hash) VALUES ('docent', ?1)", [&hash]).unwrap();
+        let user_id = get_user_id(&conn, "docent").unwrap();
+        conn.execute(
+            "INSERT INTO passwords (user_id, account, username, password_encrypted) VALUES (?1, 'github.com', 'docent', ?2)",
+            rusqlite::params![user_id, headerless("Tajne", &legacy_key)],
+        ).unwrap();
+        let entry_id = conn.last_insert_rowid();
+        conn.execute(
+            "INSERT INTO passwords (user_id, account, username, password_encrypted) VALUES (?1, 'gitlab.com', 'docent', ?2)",
+            rusqlite::params![user_id, headerless("Cudzie", &[7u8; 32])],
+        ).unwrap();
+        let foreign_id = conn.last_insert_rowid();
+
+        let session = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let encrypted: Vec<u8> = conn
+            .query_row("SELECT password_encrypted FROM passwords WHERE id = ?1", [entry_id], |row| row.get(0))
+            .unwrap();
+
+        assert!(decrypt(&encrypted, &legacy_key, &[]).is_err());
+        assert_eq!(get_entry(&conn, &session, entry_id).unwrap().password.as_str(), "Tajne");
+        assert_eq!(unreadable_entries(&conn, &session).unwrap(), vec![foreign_id]);
+        assert_eq!(list_entries(&conn, &session).unwrap().len(), 1);
+
+        let again = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        assert_eq!(session.key, again.key);
+    }
+
+    #[test]
+    fn test_password_derived_vault_rewrapped_on_login() {
+        let conn = initialize_db(":memory:").unwrap();
+
+        let (hash, _) = hash_password("DocentoveHeslo", &KdfParams::LEGACY).unwrap();
+        let salt = generate_salt();
+        let derived_key = derive_key_from_password("DocentoveHeslo", &salt, &KdfParams::LEGACY).unwrap();
+        conn.execute(
+            "INSERT INTO users (username, password_hash, key_salt) VALUES ('docent', ?1, ?2)",
+            [&hash, &salt],
+        ).unwrap();
+        let user_id = get_user_id(&conn, "docent").unwrap();
+        conn.execute(
+            "INSERT INTO passwords (user_id, account, username, password_encrypted) VALUES (?1, 'github.com', 'docent', ?2)",
+            rusqlite::params![user_id, headerless("Tajne", &derived_key)],
+        ).unwrap();
+        let entry_id = conn.last_insert_rowid();
+
+        let session = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let wrapped: Vec<u8> = conn
+            .query_row("SELECT wrapped_key FROM user_keys WHERE user_id = ?1", [user_id], |row| row.get(0))
+            .unwrap();
+
+        assert_ne!(session.key, derived_key);
+        assert_eq!(get_entry(&conn, &session, entry_id).unwrap().password.as_str(), "Tajne");
+        assert!(decrypt_bytes(&wrapped, &derived_key, &[]).is_err());
+    }
+
+    #[test]
+    fn test_rotate_keys_reencrypts_vault() {
+        let conn = initialize_db(":memory:").unwrap();
+        let mut session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let entry = insert_entry(&conn, &session, "github.com", "docent", "Tajne", None).unwrap();
+        let old_key = session.key.clone();
+
+        assert!(!rotation_due(&conn, session.user_id).unwrap());
+        rotate_keys(&conn, &mut session).unwrap();
+
+        assert_eq!(session.key_version, 2);
+        assert_ne!(session.key, old_key);
+        assert_eq!(get_entry(&conn, &session, entry.id).unwrap().password.as_str(), "Tajne");
+
+        let again = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        assert_eq!(again.key_version, 2);
+        assert_eq!(again.key, session.key);
+    }
+
+    #[test]
+    fn test_rotation_due_after_interval() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+
+        set_rotation_interval(&conn, session.user_id, 30).unwrap();
+        conn.execute("UPDATE users SET rotated_at = rotated_at - 31 * 24 * 60 * 60", []).unwrap();
+        assert!(rotation_due(&conn, session.user_id).unwrap());
+
+        set_rotation_interval(&conn, session.user_id, 0).unwrap();
+        assert!(!rotation_due(&conn, session.user_id).unwrap());
+    }
+
+    #[test]
+    fn test_change_master_password() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let entry = insert_entry(&conn, &session, "github.com", "docent", "Tajne", None).unwrap();
+
+        assert!(matches!(change_master_password(&conn, session.user_id, "ZleHeslo", "NoveHeslo"), Err(Error::Authentication)));
+        let mut changed = change_master_password(&conn, session.user_id, "DocentoveHeslo", "NoveHeslo").unwrap();
+        assert_eq!(changed.key, session.key);
+
+        assert!(login_user(&conn, "docent", "DocentoveHeslo").is_err());
+        rotate_keys(&conn, &mut changed).unwrap();
+
+        let relogged = login_user(&conn, "docent", "NoveHeslo").unwrap();
+        assert_eq!(relogged.key, changed.key);
+        assert_eq!(get_entry(&conn, &relogged, entry.id).unwrap().password.as_str(), "Tajne");
+    }
+
+    #[test]
+    fn test_weak_kdf_params_upgraded_on_login() {
+        let conn = initialize_db(":memory:").unwrap();
+
+        let (hash, _) = hash_password("DocentoveHeslo", &KdfParams::LEGACY).unwrap();
+        conn.execute("INSERT INTO users (username, password_hash) VALUES ('docent', ?1)", [&hash]).unwrap();
+
+        let session = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let (stored_hash, m_cost, t_cost): (String, u32, u32) = conn
+            .query_row("SELECT password_hash, kdf_m_cost, kdf_t_cost FROM users", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+
+        // Upgraded to what accounts registered on this machine get
+        let calibrated = calibrated_params();
+        assert_eq!((m_cost, t_cost), (calibrated.m_cost, calibrated.t_cost));
+        assert!(stored_hash.contains(&format!("m={}", calibrated.m_cost)));
+
+        let again = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        assert_eq!(again.key, session.key);
+    }
+
+    #[test]
+    fn test_calibration_never_below_policy() {
+        assert_eq!(calibrate(std::time::Duration::ZERO), KdfParams::default());
+    }
+
+    #[test]
+    fn test_vault_uses_cipher_chosen_at_registration() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register_user_with_params(
+            &conn,
+            "docent",
+            "DocentoveHeslo",
+            &KdfParams::LEGACY,
+            Algorithm::XChaCha20Poly1305,
+        ).unwrap();
+        let entry = insert_entry(&conn, &session, "github.com", "docent", "Tajne", None).unwrap();
+
+        let relogged = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let encrypted: Vec<u8> = conn
+            .query_row("SELECT password_encrypted FROM passwords WHERE id = ?1", [entry.id], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(relogged.algorithm, Algorithm::XChaCha20Poly1305);
+        assert_eq!(read_header(&encrypted).unwrap().algorithm, Algorithm::XChaCha20Poly1305);
+        assert_eq!(get_entry(&conn, &relogged, entry.id).unwrap().password.as_str(), "Tajne");
+    }
+
+    #[test]
+    fn test_login_errors_are_typed() {
+        let conn = initialize_db(":memory:").unwrap();
+        register(&conn, "docent", "DocentoveHeslo").unwrap();
+        conn.execute("INSERT INTO users (username, password_hash) VALUES ('broken', 'not a hash')", []).unwrap();
+
+        assert!(matches!(login_user(&conn, "docent", "ZleHeslo"), Err(Error::Authentication)));
+        assert!(matches!(login_user(&conn, "nobody", "ZleHeslo"), Err(Error::Authentication)));
+        assert!(matches!(login_user(&conn, "broken", "ZleHeslo"), Err(Error::Encoding(_))));
+    }
+
+    #[test]
+    fn test_swapped_ciphertexts_rejected() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let first = insert_entry(&conn, &session, "github.com", "docent", "Tajne", None).unwrap();
+        let second = insert_entry(&conn, &session, "gitlab.com", "docent", "Ine", None).unwrap();
+
+        conn.execute(
+            "UPDATE passwords SET password_encrypted = (SELECT password_encrypted FROM passwords WHERE id = ?1) WHERE id = ?2",
+            [first.id, second.id],
+        ).unwrap();
+
+        assert_eq!(get_entry(&conn, &session, first.id).unwrap().password.as_str(), "Tajne");
+        assert!(matches!(get_entry(&conn, &session, second.id), Err(Error::Aead)));
+    }
+
+    #[test]
+    fn test_metadata_encrypted() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let upper = insert_entry(&conn, &session, "GitHub.com", "docent", "Tajne", None).unwrap();
+        let mut admin = insert_entry(&conn, &session, "github.com", "admin", "Ine", None).unwrap();
+        insert_entry(&conn, &session, "gitlab.com", "docent", "Dalsie", None).unwrap();
+
+        let plaintext: i64 = conn
+            .query_row("SELECT COUNT(*) FROM passwords WHERE account != '' OR username != ''", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(plaintext, 0);
+        assert_eq!(search_entries(&conn, &session, "github.com").unwrap().len(), 2);
+
+        admin.username = "root".to_string();
+        admin.password = SecretString::from("Nove".to_string());
+        update_entry(&conn, &session, &admin).unwrap();
+        delete_entry(&conn, &session, upper.id).unwrap();
+
+        let remaining = list_entries(&conn, &session).unwrap();
+        assert_eq!(remaining.len(), 2);
+        let root = get_entry(&conn, &session, admin.id).unwrap();
+        assert_eq!((root.username.as_str(), root.password.as_str()), ("root", "Nove"));
+        assert!(get_entry(&conn, &session, upper.id).is_err());
+    }
+
+    #[test]
+    fn test_plaintext_metadata_encrypted_on_login() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let entry = insert_entry(&conn, &session, "github.com", "docent", "Tajne", None).unwrap();
+        conn.execute(
+            "UPDATE passwords SET account = 'github.com', username = 'docent', account_encrypted = NULL, username_encrypted = NULL",
+            [],
+        ).unwrap();
+        conn.execute("DELETE FROM user_keys WHERE version = 0", []).unwrap();
+        conn.execute("UPDATE users SET metadata_encrypted = 0", []).unwrap();
+
+        let relogged = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let (account, username): (String, String) = conn
+            .query_row("SELECT account, username FROM passwords", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+
+        assert_eq!((account.as_str(), username.as_str()), ("", ""));
+        assert_eq!(search_entries(&conn, &relogged, "GITHUB.COM").unwrap()[0].username, "docent");
+        assert_eq!(get_entry(&conn, &relogged, entry.id).unwrap().password.as_str(), "Tajne");
+    }
+
+    #[test]
+    fn test_unbound_entries_rewritten_on_login() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        conn.execute(
+            "INSERT INTO passwords (user_id, account, username, password_encrypted) VALUES (?1, 'github.com', 'docent', ?2)",
+            rusqlite::params![session.user_id, encrypt("Tajne", &session.key, Algorithm::Aes256Gcm, 1, &[]).unwrap()],
+        ).unwrap();
+        let entry_id = conn.last_insert_rowid();
+        conn.execute("UPDATE users SET entries_bound = 0, metadata_encrypted = 0", []).unwrap();
+
+        let relogged = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let encrypted: Vec<u8> = conn
+            .query_row("SELECT password_encrypted FROM passwords WHERE id = ?1", [entry_id], |row| row.get(0))
+            .unwrap();
+
+        assert!(decrypt(&encrypted, &relogged.key, &[]).is_err());
+        assert_eq!(get_entry(&conn, &relogged, entry_id).unwrap().password.as_str(), "Tajne");
+    }
+
+    #[test]
+    fn test_totp_rfc6238_vectors() {
+        let secret = b"12345678901234567890";
+
+        assert_eq!(totp::hotp(secret, 59 / 30, 8), "94287082");
+        assert_eq!(totp::hotp(secret, 1111111109 / 30, 8), "07081804");
+        assert_eq!(totp::hotp(secret, 2000000000 / 30, 8), "69279037");
+        assert_eq!(totp::totp(secret, 59), "287082");
+    }
+
+    #[test]
+    fn test_totp_login_step() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let secret = totp::generate_secret();
+        let now = totp::unix_time();
+
+        assert!(enable_totp(&conn, &session, &secret, "000000x").is_err());
+        assert!(!totp_enabled(&conn, session.user_id).unwrap());
+        enable_totp(&conn, &session, &secret, &totp::totp(&secret, now)).unwrap();
+        assert!(totp_enabled(&conn, session.user_id).unwrap());
+
+        let relogged = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        // The enrollment code is spent, the next period is still within the allowed skew
+        assert!(verify_totp(&conn, &relogged, &totp::totp(&secret, now)).is_err());
+        verify_totp(&conn, &relogged, &totp::totp(&secret, now + totp::PERIOD)).unwrap();
+        assert!(verify_totp(&conn, &relogged, &totp::totp(&secret, now + totp::PERIOD)).is_err());
+        assert!(verify_totp(&conn, &relogged, &totp::totp(&secret, now + 5 * totp::PERIOD)).is_err());
+
+        // Turning it off takes a fresh code as well
+        assert!(matches!(disable_totp(&conn, &relogged, "000000"), Err(Error::Authentication)));
+        assert!(disable_totp(&conn, &relogged, &totp::totp(&secret, now + totp::PERIOD)).is_err());
+        assert!(totp_enabled(&conn, relogged.user_id).unwrap());
+        conn.execute("UPDATE users SET totp_last_step = NULL", []).unwrap();
+        conn.execute("DELETE FROM login_attempts", []).unwrap();
+        disable_totp(&conn, &relogged, &totp::totp(&secret, now)).unwrap();
+        assert!(!totp_enabled(&conn, relogged.user_id).unwrap());
+    }
+
+    #[test]
+    fn test_wrong_totp_codes_throttled() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let secret = totp::generate_secret();
+        let now = totp::unix_time();
+        enable_totp(&conn, &session, &secret, &totp::totp(&secret, now)).unwrap();
+
+        for _ in 0..3 {
+            assert!(matches!(verify_totp(&conn, &session, "000000x"), Err(Error::Authentication)));
+        }
+        // Logging in again with the right password does not buy more guesses
+        let relogged = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        // Upgrading the key derivation may outlast the wait, so the failures are made recent again
+        conn.execute("UPDATE login_attempts SET last_failed_login = ?1", [totp::unix_time() as i64]).unwrap();
+        assert!(matches!(verify_totp(&conn, &relogged, &totp::totp(&secret, now + totp::PERIOD)), Err(Error::Throttled(_))));
+
+        conn.execute("UPDATE login_attempts SET last_failed_login = last_failed_login - 60", []).unwrap();
+        verify_totp(&conn, &relogged, &totp::totp(&secret, now + totp::PERIOD)).unwrap();
+        let left: i64 = conn.query_row("SELECT COUNT(*) FROM login_attempts", [], |row| row.get(0)).unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn test_recovery_code_used_once() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let secret = totp::generate_secret();
+        enable_totp(&conn, &session, &secret, &totp::totp(&secret, totp::unix_time())).unwrap();
+
+        let codes = generate_recovery_codes(&conn, &session).unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        let stored: String = conn.query_row("SELECT code_hash FROM recovery_codes LIMIT 1", [], |row| row.get(0)).unwrap();
+        assert!(!stored.contains(codes[0].as_str()));
+
+        let relogged = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        assert!(matches!(use_recovery_code(&conn, &relogged, "aaaa-aaaa-aaaa"), Err(Error::Authentication)));
+        use_recovery_code(&conn, &relogged, &codes[3].to_uppercase()).unwrap();
+        assert!(use_recovery_code(&conn, &relogged, &codes[3]).is_err());
+        assert_eq!(recovery_codes_left(&conn, relogged.user_id).unwrap(), RECOVERY_CODE_COUNT as i64 - 1);
+        assert!(totp_reenrollment_required(&conn, relogged.user_id).unwrap());
+        assert!(!totp_enabled(&conn, relogged.user_id).unwrap());
+
+        // The master password stays, only the authenticator is replaced
+        let again = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let secret = totp::generate_secret();
+        enable_totp(&conn, &again, &secret, &totp::totp(&secret, totp::unix_time())).unwrap();
+        assert!(!totp_reenrollment_required(&conn, relogged.user_id).unwrap());
+
+        let regenerated = generate_recovery_codes(&conn, &relogged).unwrap();
+        assert!(use_recovery_code(&conn, &relogged, &codes[0]).is_err());
+        use_recovery_code(&conn, &relogged, &regenerated[0]).unwrap();
+
+        // Guesses share the limit of authentication codes
+        for _ in 0..2 {
+            assert!(matches!(use_recovery_code(&conn, &relogged, "aaaa-aaaa-aaaa"), Err(Error::Authentication)));
+        }
+        assert!(matches!(verify_totp(&conn, &relogged, "000000"), Err(Error::Authentication)));
+        assert!(matches!(use_recovery_code(&conn, &relogged, &regenerated[1]), Err(Error::Throttled(_))));
+    }
+
+    #[test]
+    fn test_recovery_key_resets_master_password() {
+        let conn = initialize_db(":memory:").unwrap();
+        let mut session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let github = insert_entry(&conn, &session, "github.com", "docent", "Tajne", None).unwrap();
+        let words = create_recovery_key(&conn, &mut session).unwrap();
+        assert_eq!(words.split_whitespace().count(), 24);
+
+        // Keys added and passwords changed after the recovery key was made stay covered by it
+        rotate_keys(&conn, &mut session).unwrap();
+        let gitlab = insert_entry(&conn, &session, "gitlab.com", "docent", "Dalsie", None).unwrap();
+        change_master_password(&conn, session.user_id, "DocentoveHeslo", "Medziheslo").unwrap();
+
+        let mut wrong = parse_recovery_words(&words).unwrap().to_vec();
+        wrong[0] ^= 1;
+        let wrong_words = recovery_words(&wrong).unwrap();
+        assert!(matches!(recover_account(&conn, "docent", &wrong_words, "NoveHeslo"), Err(Error::Authentication)));
+        assert!(matches!(recover_account(&conn, "docent", "not a recovery key", "NoveHeslo"), Err(Error::Authentication)));
+        // A valid phrase of 12 words carries only 16 bytes
+        let short = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        assert!(parse_recovery_words(short).is_err());
+        assert!(matches!(recover_account(&conn, "docent", short, "NoveHeslo"), Err(Error::Authentication)));
+
+        let recovered = recover_account(&conn, "docent", &words.to_uppercase(), "NoveHeslo").unwrap();
+        assert_eq!(get_entry(&conn, &recovered, github.id).unwrap().password.as_str(), "Tajne");
+        assert!(login_user(&conn, "docent", "Medziheslo").is_err());
+
+        let relogged = login_user(&conn, "docent", "NoveHeslo").unwrap();
+        assert_eq!(get_entry(&conn, &relogged, gitlab.id).unwrap().password.as_str(), "Dalsie");
+        recover_account(&conn, "docent", &words, "TretieHeslo").unwrap();
+    }
+
+    #[test]
+    fn test_key_file_required_to_unlock() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let entry = insert_entry(&conn, &session, "github.com", "docent", "Tajne", None).unwrap();
+        let key_file = generate_key();
+
+        set_key_file(&conn, session.user_id, "DocentoveHeslo", None, Some(&key_file)).unwrap();
+        assert!(key_file_required(&conn, session.user_id).unwrap());
+        assert!(matches!(login_user(&conn, "docent", "DocentoveHeslo"), Err(Error::KeyFileRequired)));
+        // Only the right password learns that a key file is missing
+        assert!(matches!(login_user(&conn, "docent", "ZleHeslo"), Err(Error::Authentication)));
+        assert!(matches!(
+            login_user_with_key_file(&conn, "docent", "DocentoveHeslo", Some(&generate_key())),
+            Err(Error::Authentication)
+        ));
+
+        let relogged = login_user_with_key_file(&conn, "docent", "DocentoveHeslo", Some(&key_file)).unwrap();
+        assert_eq!(get_entry(&conn, &relogged, entry.id).unwrap().password.as_str(), "Tajne");
+
+        // Hashes of the password together with the key file are checked as such once more, then rewritten
+        let (composite_hash, _) = hash_password(&composite_password("DocentoveHeslo", &key_file), &KdfParams::LEGACY).unwrap();
+        conn.execute("UPDATE users SET password_hash = ?1, hash_includes_key_file = 1", [&composite_hash]).unwrap();
+        login_user_with_key_file(&conn, "docent", "DocentoveHeslo", Some(&key_file)).unwrap();
+        let rewritten: bool = conn.query_row("SELECT hash_includes_key_file FROM users", [], |row| row.get(0)).unwrap();
+        assert!(!rewritten);
+        assert!(matches!(login_user(&conn, "docent", "DocentoveHeslo"), Err(Error::KeyFileRequired)));
+
+        change_master_password_with_key_file(&conn, relogged.user_id, "DocentoveHeslo", "NoveHeslo", Some(&key_file)).unwrap();
+        assert!(login_user_with_key_file(&conn, "docent", "NoveHeslo", Some(&key_file)).is_ok());
+
+        set_key_file(&conn, relogged.user_id, "NoveHeslo", Some(&key_file), None).unwrap();
+        assert!(!key_file_required(&conn, relogged.user_id).unwrap());
+        assert!(login_user(&conn, "docent", "NoveHeslo").is_ok());
+    }
+
+    #[test]
+    fn test_otpauth_uri_codes() {
+        let sha256 = totp::encode_secret(b"12345678901234567890123456789012");
+        let otp = totp::OtpAuth::parse(&format!("otpauth://totp/ACME%20Co:docent?secret={}&algorithm=SHA256&digits=8", sha256)).unwrap();
+        assert_eq!(otp.label, "ACME Co:docent");
+        assert_eq!(otp.code(59), "46119246");
+        assert_eq!(otp.seconds_left(59), Some(1));
+        assert_eq!(totp::OtpAuth::parse(&otp.to_uri()).unwrap().code(1111111109), otp.code(1111111109));
+
+        let hotp = totp::OtpAuth::parse("otpauth://hotp/docent?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&counter=1").unwrap();
+        assert_eq!(hotp.code(0), "287082");
+        assert_eq!(hotp.next_counter().code(0), "359152");
+        assert_eq!(hotp.seconds_left(0), None);
+
+        assert!(totp::OtpAuth::parse("otpauth://hotp/docent?secret=GEZDGNBV").is_err());
+        assert!(totp::OtpAuth::parse("https://totp/docent?secret=GEZDGNBV").is_err());
+        assert!(totp::OtpAuth::parse("otpauth://totp/docent?secret=not-base32").is_err());
+    }
+
+    #[test]
+    fn test_entry_otp_survives_rotation() {
+        let conn = initialize_db(":memory:").unwrap();
+        let mut session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let mut entry = insert_entry(&conn, &session, "github.com", "docent", "Tajne", None).unwrap();
+        assert!(entry.otp.is_none());
+
+        let otp = totp::OtpAuth::parse("otpauth://totp/GitHub:docent?secret=JBSWY3DPEHPK3PXP&issuer=GitHub").unwrap();
+        entry.otp = Some(otp.clone());
+        let mut entry = update_entry(&conn, &session, &entry).unwrap();
+        let stored: Vec<u8> = conn.query_row("SELECT otp_encrypted FROM passwords", [], |row| row.get(0)).unwrap();
+        assert!(!String::from_utf8_lossy(&stored).contains("JBSWY3DPEHPK3PXP"));
+
+        rotate_keys(&conn, &mut session).unwrap();
+        entry.password = SecretString::from("Nove".to_string());
+        update_entry(&conn, &session, &entry).unwrap();
+
+        let relogged = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let mut entry = get_entry(&conn, &relogged, entry.id).unwrap();
+        let restored = entry.otp.clone().unwrap();
+        assert_eq!(restored.issuer.as_deref(), Some("GitHub"));
+        assert_eq!(restored.code(1111111109), otp.code(1111111109));
+
+        entry.otp = None;
+        update_entry(&conn, &relogged, &entry).unwrap();
+        assert!(get_entry(&conn, &relogged, entry.id).unwrap().otp.is_none());
+    }
+
+    #[test]
+    fn test_failed_logins_throttled() {
+        let conn = initialize_db(":memory:").unwrap();
+        register(&conn, "docent", "DocentoveHeslo").unwrap();
+
+        for _ in 0..3 {
+            assert!(matches!(login_user(&conn, "docent", "ZleHeslo"), Err(Error::Authentication)));
+        }
+        // Even the right password has to wait now
+        assert!(matches!(login_user(&conn, "docent", "DocentoveHeslo"), Err(Error::Throttled(seconds)) if seconds <= 2));
+
+        conn.execute("UPDATE login_attempts SET last_failed_login = last_failed_login - 2", []).unwrap();
+        assert!(login_user(&conn, "docent", "ZleHeslo").is_err());
+        assert!(matches!(login_user(&conn, "docent", "ZleHeslo"), Err(Error::Throttled(seconds)) if seconds > 2 && seconds <= 4));
+
+        conn.execute("UPDATE login_attempts SET failed_logins = 10", []).unwrap();
+        assert!(matches!(login_user(&conn, "docent", "DocentoveHeslo"), Err(Error::Throttled(seconds)) if seconds > 800));
+
+        conn.execute("UPDATE login_attempts SET last_failed_login = last_failed_login - 15 * 60", []).unwrap();
+        login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let failed: i64 = conn.query_row("SELECT COUNT(*) FROM login_attempts", [], |row| row.get(0)).unwrap();
+        assert_eq!(failed, 0);
+
+        // A name only ever guessed is forgotten a day later by whichever check comes next, even one that succeeds
+        assert!(matches!(login_user(&conn, "nikto", "ZleHeslo"), Err(Error::Authentication)));
+        conn.execute("UPDATE login_attempts SET last_failed_login = last_failed_login - 24 * 60 * 60 - 1", []).unwrap();
+        login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        let failed: i64 = conn.query_row("SELECT COUNT(*) FROM login_attempts", [], |row| row.get(0)).unwrap();
+        assert_eq!(failed, 0);
+    }
+
+    #[test]
+    fn test_changing_password_throttled() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+
+        for _ in 0..3 {
+            assert!(matches!(change_master_password(&conn, session.user_id, "ZleHeslo", "NoveHeslo"), Err(Error::Authentication)));
+        }
+        assert!(matches!(change_master_password(&conn, session.user_id, "ZleHeslo", "NoveHeslo"), Err(Error::Throttled(_))));
+        assert!(matches!(delete_user(&conn, session.user_id, "DocentoveHeslo"), Err(Error::Throttled(_))));
+        assert!(matches!(login_user(&conn, "docent", "DocentoveHeslo"), Err(Error::Throttled(_))));
+    }
+
+    #[test]
+    fn test_unknown_user_login_timing() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register_user(&conn, "kustod", "KustodoveHeslo").unwrap();
+        assert!(matches!(register_user(&conn, "kustod", "InéHeslo"), Err(Error::UsernameUnavailable)));
+        // Accounts from before per-user parameters, one still on them and one upgraded by logging in
+        let (hash, _) = hash_password("StareHeslo", &KdfParams::LEGACY).unwrap();
+        conn.execute("INSERT INTO users (username, password_hash) VALUES ('archivar', ?1), ('pisar', ?1)", [&hash]).unwrap();
+        login_user(&conn, "pisar", "StareHeslo").unwrap();
+
+        let fastest = |username: &str| {
+            (0..3)
+                .map(|_| {
+                    conn.execute("DELETE FROM login_attempts", []).unwrap();
+                    let start = std::time::Instant::now();
+                    assert!(matches!(login_user(&conn, username, "ZleHeslo"), Err(Error::Authentication)));
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let unknown = fastest("nikto");
+        for username in ["kustod", "archivar", "pisar"] {
+            let known = fastest(username);
+            // Every path runs at least one Argon2 derivation with the parameters of this machine
+            assert!(unknown > known / 2 && unknown < known * 2, "{} {:?}, unknown {:?}", username, known, unknown);
+        }
+
+        // Both are throttled after the same number of failures
+        conn.execute("DELETE FROM login_attempts", []).unwrap();
+        for username in ["kustod", "nikto"] {
+            for _ in 0..3 {
+                assert!(matches!(login_user(&conn, username, "ZleHeslo"), Err(Error::Authentication)));
+            }
+            assert!(matches!(login_user(&conn, username, "ZleHeslo"), Err(Error::Throttled(_))));
+        }
+
+        // A key file stays hidden behind a wrong password
+        conn.execute("DELETE FROM login_attempts", []).unwrap();
+        set_key_file(&conn, session.user_id, "KustodoveHeslo", None, Some(&generate_key())).unwrap();
+        assert!(matches!(login_user(&conn, "kustod", "ZleHeslo"), Err(Error::Authentication)));
+        assert!(matches!(login_user(&conn, "nikto", "ZleHeslo"), Err(Error::Authentication)));
+    }
+
+    #[test]
+    fn test_remembered_device_unlocks() {
+        let dir = std::env::temp_dir().join(format!("password_manager_keys_{}", std::process::id()));
+        let store = FileKeyStore::new(&dir);
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "rektor", "RektoroveHeslo").unwrap();
+        let entry = insert_entry(&conn, &session, "github.com", "rektor", "Tajne", None).unwrap();
+
+        assert!(login_remembered(&conn, &store, "rektor").unwrap().is_none());
+        remember_device(&store, "rektor", &session).unwrap();
+        assert!(device_remembered(&store, "rektor").unwrap());
+        assert!(login_remembered(&conn, &store, "dekan").unwrap().is_none());
+
+        let remembered = login_remembered(&conn, &store, "rektor").unwrap().unwrap();
+        assert_eq!(get_entry(&conn, &remembered, entry.id).unwrap().password.as_str(), "Tajne");
+
+        // A new master password makes the saved key stale
+        change_master_password(&conn, session.user_id, "RektoroveHeslo", "NoveHeslo").unwrap();
+        assert!(matches!(login_remembered(&conn, &store, "rektor"), Err(Error::Authentication)));
+        assert!(!device_remembered(&store, "rektor").unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_secret_service_provider() {
+        use std::collections::HashMap;
+        use std::io::BufRead;
+        use secret_service::EncryptionType;
+        use secret_service::blocking::SecretService;
+
+        // A private bus, so the test neither needs nor disturbs a desktop session
+        let Ok(mut daemon) = std::process::Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+        else {
+            eprintln!("dbus-daemon is not installed, skipping");
+            return;
+        };
+        let mut address = String::new();
+        std::io::BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        let address = address.trim().to_string();
+
+        let path = std::env::temp_dir().join(format!("password_manager_provider_{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let conn = initialize_db(path).unwrap();
+        let session = register(&conn, "rektor", "RektoroveHeslo").unwrap();
+        insert_entry(&conn, &session, "github.com", "rektor", "Tajne", None).unwrap();
+
+        let vaults = UnlockedVaults::default();
+        let provider = Provider::new(initialize_db(path).unwrap(), vaults.clone());
+        let bus = provider::connect(Some(&address)).unwrap();
+        std::thread::spawn(move || provider.serve(&bus));
+
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", &address);
+        let client = SecretService::connect(EncryptionType::Plain).unwrap();
+        let collection = client.get_default_collection().unwrap();
+        assert!(collection.is_locked().unwrap());
+        // Only the master password unlocks, and the bus cannot ask for it
+        assert!(collection.unlock().is_err());
+
+        vaults.unlock(session.clone());
+        assert!(!collection.is_locked().unwrap());
+        let found = client.search_items(HashMap::from([("account", "github.com")])).unwrap();
+        assert_eq!(found.unlocked.len(), 1);
+        assert_eq!(found.unlocked[0].get_secret().unwrap(), b"Tajne");
+
+        let attributes = HashMap::from([("server", "example.com"), ("user", "docent")]);
+        collection.create_item("Git", attributes.clone(), b"Heslo", true, "text/plain").unwrap();
+        collection.create_item("Git", attributes.clone(), b"NoveHeslo", true, "text/plain").unwrap();
+        assert_eq!(collection.search_items(attributes).unwrap().len(), 1);
+        let stored = search_entries(&conn, &session, "Git").unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored.iter().find(|entry| entry.account == "Git").unwrap().password.as_str(), "NoveHeslo");
+
+        vaults.lock(session.user_id);
+        assert!(found.unlocked[0].get_secret().is_err());
+
+        daemon.kill().unwrap();
+        daemon.wait().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_detects_tampering() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "kvestor", "KvestoroveHeslo").unwrap();
+        let mut entry = insert_entry(&conn, &session, "github.com", "kvestor", "Tajne", None).unwrap();
+        let old: Vec<u8> = conn.query_row("SELECT password_encrypted FROM passwords", [], |row| row.get(0)).unwrap();
+        entry.password = SecretString::from("NoveTajne".to_string());
+        update_entry(&conn, &session, &entry).unwrap();
+        insert_entry(&conn, &session, "gitlab.com", "kvestor", "Ine", None).unwrap();
+
+        let counter = verify_manifest(&conn, &session, None).unwrap();
+        assert_eq!(manifest_counter(&conn, session.user_id).unwrap(), Some(counter));
+
+        // An old ciphertext put back in place
+        conn.execute("UPDATE passwords SET password_encrypted = ?1 WHERE id = (SELECT MIN(id) FROM passwords)", [&old]).unwrap();
+        assert!(matches!(verify_manifest(&conn, &session, Some(counter)), Err(Error::Tampered(_))));
+
+        // A deleted row, once the user chose to trust the vault as it was
+        let counter = reseal_manifest(&conn, &session, Some(counter)).unwrap();
+        assert_eq!(verify_manifest(&conn, &session, Some(counter)).unwrap(), counter);
+        conn.execute("DELETE FROM passwords WHERE id = (SELECT MAX(id) FROM passwords)", []).unwrap();
+        assert!(matches!(verify_manifest(&conn, &session, Some(counter)), Err(Error::Tampered(_))));
+
+        // A copy of the database from before the last change
+        reseal_manifest(&conn, &session, Some(counter)).unwrap();
+        assert!(matches!(verify_manifest(&conn, &session, Some(counter + 5)), Err(Error::Tampered(_))));
+        conn.execute("DELETE FROM vault_manifests", []).unwrap();
+        assert!(matches!(verify_manifest(&conn, &session, Some(counter)), Err(Error::Tampered(_))));
+    }
+
+    #[test]
+    fn test_original_database_upgraded() {
+        let path = std::env::temp_dir().join(format!("password_manager_original_{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute_batch(include_str!("../tests/fixtures/schema_original.sql")).unwrap();
+        let (hash, _) = hash_password("DocentoveHeslo", &KdfParams::LEGACY).unwrap();
+        conn.execute("INSERT INTO users (username, password_hash) VALUES ('docent', ?1)", [&hash]).unwrap();
+        conn.execute(
+            "INSERT INTO passwords (user_id, account, username, password_encrypted) VALUES (1, 'github.com', 'docent', ?1)",
+            [headerless("Tajne", &[42u8; 32])],
+        ).unwrap();
+        drop(conn);
+
+        let conn = initialize_db(path).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        let session = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        assert_eq!(list_entries(&conn, &session).unwrap()[0].password.as_str(), "Tajne");
+
+        // The copy taken before upgrading still held the plaintext account, so it went once the upgrade did
+        assert!(backups(path, 0).is_empty());
+        let account: String = conn.query_row("SELECT account FROM passwords", [], |row| row.get(0)).unwrap();
+        assert_eq!(account, "");
+
+        drop(conn);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_unversioned_database_upgraded() {
+        let path = std::env::temp_dir().join(format!("password_manager_unversioned_{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let seed_path = format!("{}.seed", path);
+
+        // A vault the library wrote, moved into the tables of the last release without a schema version
+        let seed = initialize_db(&seed_path).unwrap();
+        let session = register(&seed, "docent", "DocentoveHeslo").unwrap();
+        insert_entry(&seed, &session, "github.com", "docent", "Tajne", None).unwrap();
+        let counter = verify_manifest(&seed, &session, None).unwrap();
+        drop(seed);
+
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute_batch(include_str!("../tests/fixtures/schema_unversioned.sql")).unwrap();
+        conn.execute("ATTACH DATABASE ?1 AS seed", [&seed_path]).unwrap();
+        for table in ["users", "passwords", "user_keys", "vault_manifests", "recovery_codes"] {
+            let columns = conn
+                .prepare("SELECT name FROM pragma_table_info(?1, 'main') WHERE name IN (SELECT name FROM pragma_table_info(?1, 'seed'))")
+                .unwrap()
+                .query_map([table], |row| row.get::<_, String>(0))
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .unwrap()
+                .join(", ");
+            conn.execute(&format!("INSERT INTO main.{0} ({1}) SELECT {1} FROM seed.{0}", table, columns), []).unwrap();
+        }
+        conn.execute("DETACH DATABASE seed", []).unwrap();
+        drop(conn);
+        std::fs::remove_file(&seed_path).unwrap();
+
+        let conn = initialize_db(path).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        let session = login_user(&conn, "docent", "DocentoveHeslo").unwrap();
+        assert_eq!(verify_manifest(&conn, &session, Some(counter)).unwrap(), counter);
+        let entry = list_entries(&conn, &session).unwrap().remove(0);
+        assert_eq!(entry.password.as_str(), "Tajne");
+        // The release kept no timestamps, so none are made up
+        assert_eq!(entry.created_at, None);
+
+        assert!(backups(path, 0).is_empty());
+
+        // Adopted at the step its columns show, it ends up with the tables of a new database
+        let columns = |conn: &rusqlite::Connection| {
+            let mut columns = conn
+                .prepare("SELECT m.name || '.' || c.name FROM sqlite_master m, pragma_table_info(m.name) c WHERE m.type = 'table'")
+                .unwrap()
+                .query_map([], |row| row.get::<_, String>(0))
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .unwrap();
+            columns.sort();
+            columns
+        };
+        assert_eq!(columns(&conn), columns(&initialize_db(":memory:").unwrap()));
+
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        drop(conn);
+        assert!(matches!(initialize_db(path), Err(Error::NewerSchema(version)) if version == SCHEMA_VERSION + 1));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_vault_entries_by_id() {
+        let conn = initialize_db(":memory:").unwrap();
+        let session = register(&conn, "docent", "DocentoveHeslo").unwrap();
+        let otp = totp::OtpAuth::parse("otpauth://totp/GitHub:docent?secret=JBSWY3DPEHPK3PXP").unwrap();
+
+        let first = insert_entry(&conn, &session, "github.com", "docent", "Prve", None).unwrap();
+        let second = insert_entry(&conn, &session, "github.com", "docent", "Druhe", Some(&otp)).unwrap();
+        assert_ne!(first.id, second.id);
+        assert!(first.created_at.is_some() && first.updated_at.is_some());
+        assert_eq!(second.otp.as_ref().unwrap().to_uri(), otp.to_uri());
+
+        let edited = VaultEntry { password: "Zmenene".into(), otp: None, ..second.clone() };
+        let updated = update_entry(&conn, &session, &edited).unwrap();
+        assert_eq!(updated.created_at, second.created_at);
+        assert!(updated.otp.is_none());
+        assert_eq!(get_entry(&conn, &session, first.id).unwrap().password.as_str(), "Prve");
+
+        delete_entry(&conn, &session, first.id).unwrap();
+        let entries = list_entries(&conn, &session).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].password.as_str(), "Zmenene");
+        assert!(get_entry(&conn, &session, first.id).is_err());
+
+        let other = register(&conn, "rektor", "RektoroveHeslo").unwrap();
+        assert!(get_entry(&conn, &other, second.id).is_err());
+        assert!(delete_entry(&conn, &other, second.id).is_err());
+        assert!(update_entry(&conn, &other, &edited).is_err());
+        assert_eq!(search_entries(&conn, &session, "GITHUB.COM").unwrap().len(), 1);
+        assert_eq!(search_entries(&conn, &session, "Hub").unwrap().len(), 1);
+        assert!(search_entries(&conn, &session, "gitlab").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_delete_user_removes_everything() {
+        let path = std::env::temp_dir().join(format!("password_manager_delete_{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let conn = initialize_db(path).unwrap();
+        let mut session = register(&conn, "odchadzajuci", "OdchadzajuceHeslo").unwrap();
+        let other = register(&conn, "rektor", "RektoroveHeslo").unwrap();
+        insert_entry(&conn, &session, "github.com", "odchadzajuci", "Tajne", None).unwrap();
+        let kept = insert_entry(&conn, &other, "gitlab.com", "rektor", "Ine", None).unwrap();
+        create_recovery_key(&conn, &mut session).unwrap();
+        generate_recovery_codes(&conn, &session).unwrap();
+
+        assert!(matches!(delete_user(&conn, session.user_id, "ZleHeslo"), Err(Error::Authentication)));
+        delete_user(&conn, session.user_id, "OdchadzajuceHeslo").unwrap();
+
+        for table in ["passwords", "user_keys", "recovery_codes", "vault_manifests"] {
+            let left: i64 = conn
+                .query_row(&format!("SELECT COUNT(*) FROM {} WHERE user_id = ?1", table), [session.user_id], |row| row.get(0))
+                .unwrap();
+            assert_eq!(left, 0, "{}", table);
+        }
+        assert!(login_user(&conn, "odchadzajuci", "OdchadzajuceHeslo").is_err());
+        let other = login_user(&conn, "rektor", "RektoroveHeslo").unwrap();
+        assert_eq!(get_entry(&conn, &other, kept.id).unwrap().password.as_str(), "Ine");
+        assert!(conn
+            .execute("INSERT INTO passwords (user_id, account, username, password_encrypted) VALUES (?1, '', '', x'')", [session.user_id])
+            .is_err());
+
+        drop(conn);
+        let file = std::fs::read(path).unwrap();
+        assert!(!file.windows(b"odchadzajuci".len()).any(|window| window == b"odchadzajuci"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_keyring_fold_rolled_back() {
+        let path = std::env::temp_dir().join(format!("password_manager_fold_{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute_batch(include_str!("../tests/fixtures/schema_original.sql")).unwrap();
+        conn.execute_batch(
+            "ALTER TABLE users ADD COLUMN key_salt TEXT;
+             ALTER TABLE users ADD COLUMN wrapped_key BLOB;
+             CREATE INDEX users_wrapped_key ON users (wrapped_key);
+             INSERT INTO users (username, password_hash, wrapped_key) VALUES ('docent', 'hash', x'00');",
+        ).unwrap();
+        drop(conn);
+
+        // An indexed column cannot be dropped, so the fold fails after copying the key
+        assert!(initialize_db(path).is_err());
+        let conn = rusqlite::Connection::open(path).unwrap();
+        let wrapped: Vec<u8> = conn.query_row("SELECT wrapped_key FROM users", [], |row| row.get(0)).unwrap();
+        assert_eq!(wrapped, vec![0]);
+        assert!(conn.query_row("SELECT COUNT(*) FROM user_keys", [], |row| row.get::<_, i64>(0)).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        drop(conn);
 
-        let decrypted = decrypt(&encrypted, &key).unwrap();
+        // A failed upgrade keeps the copy taken before it
+        let backup = backups(path, 0);
+        assert_eq!(backup.len(), 1);
+        let old = rusqlite::Connection::open(&backup[0]).unwrap();
+        let wrapped: Vec<u8> = old.query_row("SELECT wrapped_key FROM users", [], |row| row.get(0)).unwrap();
+        assert_eq!(wrapped, vec![0]);
+        drop(old);
 
-        assert_eq!(plaintext, decrypted);
+        std::fs::remove_file(&backup[0]).unwrap();
+        std::fs::remove_file(path).unwrap();
     }
 }
\ No newline at end of file
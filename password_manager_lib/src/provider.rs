@@ -0,0 +1,537 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use rusqlite::{Connection, params};
+use zbus::blocking::{self, ConnectionBuilder, MessageIterator};
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value};
+use zbus::{Message, MessageType};
+use crate::database::{self, SecretItem, Session};
+use crate::secret::SecretKey;
+use crate::{Error, Result};
+
+/// Well-known bus name of the freedesktop Secret Service.
+pub const SERVICE_NAME: &str = "org.freedesktop.secrets";
+
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const DEFAULT_ALIAS_PATH: &str = "/org/freedesktop/secrets/aliases/default";
+const COLLECTION_PREFIX: &str = "/org/freedesktop/secrets/collection/";
+const SESSION_PREFIX: &str = "/org/freedesktop/secrets/session/";
+const PROMPT_PREFIX: &str = "/org/freedesktop/secrets/prompt/";
+// Path returned where the specification expects a prompt that is not needed
+const NO_PROMPT: &str = "/";
+
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const PROMPT_INTERFACE: &str = "org.freedesktop.Secret.Prompt";
+
+const LABEL_PROPERTY: &str = "org.freedesktop.Secret.Item.Label";
+const ATTRIBUTES_PROPERTY: &str = "org.freedesktop.Secret.Item.Attributes";
+const CONTENT_TYPE: &str = "text/plain; charset=utf8";
+
+// Session path, parameters, value and content type, the (oayays) struct of the specification
+type Secret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+/// Vaults the provider can read. A user's collection is unlocked while their session is in here,
+/// which only a login with the master password can put there.
+#[derive(Clone, Default)]
+pub struct UnlockedVaults(Arc<Mutex<HashMap<i64, Session>>>);
+
+impl UnlockedVaults {
+    pub fn unlock(&self, session: Session) {
+        self.sessions().insert(session.user_id, session);
+    }
+
+    pub fn lock(&self, user_id: i64) {
+        self.sessions().remove(&user_id);
+    }
+
+    fn session(&self, user_id: i64) -> Option<Session> {
+        self.sessions().get(&user_id).cloned()
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, HashMap<i64, Session>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Connects to the session bus, or the bus at `address`, and claims the Secret Service name.
+pub fn connect(address: Option<&str>) -> Result<blocking::Connection> {
+    let builder = match address {
+        Some(address) => ConnectionBuilder::address(address)?,
+        None => ConnectionBuilder::session()?,
+    };
+    Ok(builder.name(SERVICE_NAME)?.build()?)
+}
+
+/// Serves the vault over the freedesktop Secret Service API (org.freedesktop.secrets).
+///
+/// Every user is a collection and every entry an item of it. Items stored by other applications
+/// keep their lookup attributes; entries made in the vault are found by `account` and `username`.
+/// Only the `plain` session algorithm is offered, so secrets cross the bus unencrypted, and secrets
+/// have to be UTF-8 text. Unlocking cannot ask for the master password over the bus: a collection
+/// stays locked until its user logs in to the password manager.
+pub struct Provider {
+    conn: Connection,
+    vaults: UnlockedVaults,
+    sessions: HashSet<String>,
+    // Objects each pending unlock prompt was asked for
+    prompts: HashMap<String, Vec<OwnedObjectPath>>,
+    next_id: u64,
+}
+
+enum Object {
+    Service,
+    Collection(i64),
+    Item(i64, i64),
+    Session(String),
+    Prompt(String),
+}
+
+// An error reply
+struct Failure {
+    name: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn new(name: &'static str, message: impl Into<String>) -> Self {
+        Failure { name, message: message.into() }
+    }
+
+    fn not_supported(message: &str) -> Self {
+        Failure::new("org.freedesktop.DBus.Error.NotSupported", message)
+    }
+
+    fn invalid_args(message: impl Into<String>) -> Self {
+        Failure::new("org.freedesktop.DBus.Error.InvalidArgs", message)
+    }
+
+    fn is_locked() -> Self {
+        Failure::new("org.freedesktop.Secret.Error.IsLocked", "The collection is locked, log in to the password manager")
+    }
+
+    fn no_session() -> Self {
+        Failure::new("org.freedesktop.Secret.Error.NoSession", "No such session")
+    }
+
+    fn no_such_object(path: &str) -> Self {
+        Failure::new("org.freedesktop.Secret.Error.NoSuchObject", format!("No such object: {}", path))
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        Failure::new("org.freedesktop.DBus.Error.Failed", err.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for Failure {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::from(err).into()
+    }
+}
+
+impl From<zbus::Error> for Failure {
+    fn from(err: zbus::Error) -> Self {
+        Error::from(err).into()
+    }
+}
+
+type Reply = std::result::Result<(), Failure>;
+
+impl Provider {
+    pub fn new(conn: Connection, vaults: UnlockedVaults) -> Self {
+        Provider { conn, vaults, sessions: HashSet::new(), prompts: HashMap::new(), next_id: 1 }
+    }
+
+    /// Answers method calls on `bus` until the connection closes.
+    pub fn serve(mut self, bus: &blocking::Connection) -> Result<()> {
+        for message in MessageIterator::from(bus) {
+            let message = message?;
+            if message.message_type() != MessageType::MethodCall {
+                continue;
+            }
+            if let Err(failure) = self.dispatch(bus, &message) {
+                // The caller may be gone already, which is no reason to stop serving others
+                bus.reply_error(&message, failure.name, &failure.message).ok();
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, bus: &blocking::Connection, message: &Message) -> Reply {
+        let path = message.path().map(|path| path.to_string()).unwrap_or_default();
+        let interface = message.interface().map(|interface| interface.to_string()).unwrap_or_default();
+        let member = message.member().map(|member| member.to_string()).unwrap_or_default();
+        let object = self.resolve(&path)?.ok_or_else(|| Failure::no_such_object(&path))?;
+
+        if interface == PROPERTIES_INTERFACE {
+            return self.properties(bus, message, &object, &member);
+        }
+
+        match (object, member.as_str()) {
+            (Object::Service, "OpenSession") => {
+                let (algorithm, _input): (String, OwnedValue) = arguments(message)?;
+                if algorithm != "plain" {
+                    return Err(Failure::not_supported("Only the plain algorithm is supported"));
+                }
+                let session = format!("{}s{}", SESSION_PREFIX, self.next_id());
+                self.sessions.insert(session.clone());
+                bus.reply(message, &(Value::from(""), object_path(session)))?;
+            }
+            (Object::Service, "CreateCollection") => {
+                return Err(Failure::not_supported("Collections are the users of the password manager"));
+            }
+            (Object::Service, "SearchItems") => {
+                let attributes: HashMap<String, String> = arguments(message)?;
+                let mut unlocked = Vec::new();
+                for user_id in self.user_ids()? {
+                    // Attributes of locked vaults are encrypted, so only unlocked ones can match
+                    if let Some(session) = self.vaults.session(user_id) {
+                        unlocked.extend(self.search(&session, &attributes)?);
+                    }
+                }
+                bus.reply(message, &(unlocked, Vec::<OwnedObjectPath>::new()))?;
+            }
+            (Object::Service, "Unlock") => {
+                let objects: Vec<OwnedObjectPath> = arguments(message)?;
+                let (unlocked, locked) = self.split_unlocked(objects)?;
+                let prompt = if locked.is_empty() {
+                    NO_PROMPT.to_string()
+                } else {
+                    let prompt = format!("{}p{}", PROMPT_PREFIX, self.next_id());
+                    self.prompts.insert(prompt.clone(), locked);
+                    prompt
+                };
+                bus.reply(message, &(unlocked, object_path(prompt)))?;
+            }
+            (Object::Service, "Lock") => {
+                let objects: Vec<OwnedObjectPath> = arguments(message)?;
+                for object in &objects {
+                    if let Some(user_id) = self.resolve(object)?.and_then(|object| object.user_id()) {
+                        self.vaults.lock(user_id);
+                    }
+                }
+                bus.reply(message, &(objects, object_path(NO_PROMPT.to_string())))?;
+            }
+            (Object::Service, "GetSecrets") => {
+                let (items, session): (Vec<OwnedObjectPath>, OwnedObjectPath) = arguments(message)?;
+                self.check_session(&session)?;
+                let mut secrets = HashMap::new();
+                for item in items {
+                    // Locked and unknown items are left out rather than failing the whole call
+                    if let Some(Object::Item(user_id, entry_id)) = self.resolve(&item)? {
+                        if let Some(vault) = self.vaults.session(user_id) {
+                            let password = database::secret_item_password(&self.conn, &vault, entry_id)?;
+                            secrets.insert(item, secret(&session, &password));
+                        }
+                    }
+                }
+                bus.reply(message, &secrets)?;
+            }
+            (Object::Service, "ReadAlias") => {
+                let name: String = arguments(message)?;
+                let collection = match name.as_str() {
+                    "default" => self.default_collection()?.map(collection_path),
+                    _ => None,
+                };
+                bus.reply(message, &collection.unwrap_or_else(|| object_path(NO_PROMPT.to_string())))?;
+            }
+            (Object::Service, "SetAlias") => {
+                return Err(Failure::not_supported("Aliases cannot be changed"));
+            }
+            (Object::Collection(_), "Delete") => {
+                return Err(Failure::not_supported("Delete the account in the password manager"));
+            }
+            (Object::Collection(user_id), "SearchItems") => {
+                let attributes: HashMap<String, String> = arguments(message)?;
+                let vault = self.vaults.session(user_id).ok_or_else(Failure::is_locked)?;
+                bus.reply(message, &self.search(&vault, &attributes)?)?;
+            }
+            (Object::Collection(user_id), "CreateItem") => {
+                let (mut properties, secret, replace): (HashMap<String, OwnedValue>, Secret, bool) = arguments(message)?;
+                let vault = self.vaults.session(user_id).ok_or_else(Failure::is_locked)?;
+                let label = match properties.remove(LABEL_PROPERTY) {
+                    Some(label) => String::try_from(label).map_err(|_| Failure::invalid_args("Label must be a string"))?,
+                    None => String::new(),
+                };
+                let attributes: HashMap<String, String> = match properties.remove(ATTRIBUTES_PROPERTY) {
+                    Some(attributes) => HashMap::try_from(attributes).map_err(|_| Failure::invalid_args("Attributes must be a{ss}"))?,
+                    None => HashMap::new(),
+                };
+                let password = self.secret_value(secret)?;
+
+                let existing = if replace {
+                    database::secret_items(&self.conn, &vault)?
+                        .into_iter()
+                        .find(|item| item.lookup_attributes() == attributes)
+                        .map(|item| item.id)
+                } else {
+                    None
+                };
+                let username = item_username(&attributes);
+                let password = std::str::from_utf8(&password).map_err(|_| Failure::invalid_args("Secrets must be UTF-8 text"))?;
+                let entry_id = database::write_secret_item(&self.conn, &vault, existing, &label, &username, password, &attributes)?;
+                bus.reply(message, &(item_path(user_id, entry_id), object_path(NO_PROMPT.to_string())))?;
+            }
+            (Object::Item(user_id, entry_id), "Delete") => {
+                let vault = self.vaults.session(user_id).ok_or_else(Failure::is_locked)?;
+                database::delete_secret_item(&self.conn, &vault, entry_id)?;
+                bus.reply(message, &object_path(NO_PROMPT.to_string()))?;
+            }
+            (Object::Item(user_id, entry_id), "GetSecret") => {
+                let session: OwnedObjectPath = arguments(message)?;
+                self.check_session(&session)?;
+                let vault = self.vaults.session(user_id).ok_or_else(Failure::is_locked)?;
+                let password = database::secret_item_password(&self.conn, &vault, entry_id)?;
+                bus.reply(message, &secret(&session, &password))?;
+            }
+            (Object::Item(user_id, entry_id), "SetSecret") => {
+                let secret: Secret = arguments(message)?;
+                let (vault, item) = self.item(user_id, entry_id)?;
+                let password = self.secret_value(secret)?;
+                let password = std::str::from_utf8(&password).map_err(|_| Failure::invalid_args("Secrets must be UTF-8 text"))?;
+                database::write_secret_item(&self.conn, &vault, Some(entry_id), &item.label, &item.username, password, &item.lookup_attributes())?;
+                bus.reply(message, &())?;
+            }
+            (Object::Session(session), "Close") => {
+                self.sessions.remove(&session);
+                bus.reply(message, &())?;
+            }
+            (Object::Prompt(prompt), "Prompt" | "Dismiss") => {
+                let objects = self.prompts.remove(&prompt).unwrap_or_default();
+                bus.reply(message, &())?;
+
+                // There is no dialog to show; whatever got unlocked by logging in meanwhile completes the prompt
+                let (unlocked, locked) = match member.as_str() {
+                    "Prompt" => self.split_unlocked(objects)?,
+                    _ => (Vec::new(), objects),
+                };
+                bus.emit_signal(None::<&str>, prompt.as_str(), PROMPT_INTERFACE, "Completed", &(!locked.is_empty(), Value::from(unlocked)))?;
+            }
+            _ => {
+                return Err(Failure::new(
+                    "org.freedesktop.DBus.Error.UnknownMethod",
+                    format!("No method {} on {}", member, path),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn properties(&mut self, bus: &blocking::Connection, message: &Message, object: &Object, member: &str) -> Reply {
+        match member {
+            "Get" => {
+                let (_interface, name): (String, String) = arguments(message)?;
+                let value = self
+                    .property_values(object)?
+                    .remove(name.as_str())
+                    .ok_or_else(|| Failure::new("org.freedesktop.DBus.Error.UnknownProperty", format!("No property {}", name)))?;
+                bus.reply(message, &value)?;
+            }
+            "GetAll" => {
+                let _interface: String = arguments(message)?;
+                bus.reply(message, &self.property_values(object)?)?;
+            }
+            "Set" => {
+                let (_interface, name, value): (String, String, OwnedValue) = arguments(message)?;
+                let &Object::Item(user_id, entry_id) = object else {
+                    return Err(Failure::new("org.freedesktop.DBus.Error.PropertyReadOnly", format!("{} is read-only", name)));
+                };
+                let (vault, mut item) = self.item(user_id, entry_id)?;
+                let mut attributes = item.lookup_attributes();
+                match name.as_str() {
+                    "Label" => item.label = String::try_from(value).map_err(|_| Failure::invalid_args("Label must be a string"))?,
+                    "Attributes" => {
+                        attributes = HashMap::try_from(value).map_err(|_| Failure::invalid_args("Attributes must be a{ss}"))?;
+                        item.username = item_username(&attributes);
+                    }
+                    _ => return Err(Failure::new("org.freedesktop.DBus.Error.PropertyReadOnly", format!("{} is read-only", name))),
+                }
+                let password = database::secret_item_password(&self.conn, &vault, entry_id)?;
+                database::write_secret_item(&self.conn, &vault, Some(entry_id), &item.label, &item.username, &password, &attributes)?;
+                bus.reply(message, &())?;
+            }
+            _ => {
+                return Err(Failure::new("org.freedesktop.DBus.Error.UnknownMethod", format!("No method {}", member)));
+            }
+        }
+        Ok(())
+    }
+
+    fn property_values(&self, object: &Object) -> std::result::Result<HashMap<&'static str, Value<'static>>, Failure> {
+        let mut values = HashMap::new();
+        match *object {
+            Object::Service => {
+                let collections: Vec<OwnedObjectPath> = self.user_ids()?.into_iter().map(collection_path).collect();
+                values.insert("Collections", Value::from(collections));
+            }
+            Object::Collection(user_id) => {
+                let vault = self.vaults.session(user_id);
+                let items: Vec<OwnedObjectPath> = match &vault {
+                    Some(vault) => database::secret_items(&self.conn, vault)?.iter().map(|item| item_path(user_id, item.id)).collect(),
+                    None => Vec::new(),
+                };
+                let label: String = self.conn.query_row("SELECT username FROM users WHERE id = ?1", params![user_id], |row| row.get(0))?;
+                values.insert("Items", Value::from(items));
+                values.insert("Label", Value::from(label));
+                values.insert("Locked", Value::from(vault.is_none()));
+                values.insert("Created", Value::from(0u64));
+                values.insert("Modified", Value::from(0u64));
+            }
+            Object::Item(user_id, entry_id) => {
+                let (_, item) = self.item(user_id, entry_id)?;
+                values.insert("Locked", Value::from(false));
+                values.insert("Attributes", Value::from(item.lookup_attributes()));
+                values.insert("Label", Value::from(item.label));
+                values.insert("Created", Value::from(0u64));
+                values.insert("Modified", Value::from(0u64));
+            }
+            Object::Session(_) | Object::Prompt(_) => {}
+        }
+        Ok(values)
+    }
+
+    fn resolve(&self, path: &str) -> std::result::Result<Option<Object>, Failure> {
+        if path == SERVICE_PATH {
+            return Ok(Some(Object::Service));
+        }
+        if path == DEFAULT_ALIAS_PATH {
+            return Ok(self.default_collection()?.map(Object::Collection));
+        }
+        if path.starts_with(SESSION_PREFIX) {
+            return Ok(self.sessions.contains(path).then(|| Object::Session(path.to_string())));
+        }
+        if path.starts_with(PROMPT_PREFIX) {
+            return Ok(self.prompts.contains_key(path).then(|| Object::Prompt(path.to_string())));
+        }
+        let Some(rest) = path.strip_prefix(COLLECTION_PREFIX) else {
+            return Ok(None);
+        };
+
+        let (collection, item) = match rest.split_once('/') {
+            Some((collection, item)) => (collection, Some(item)),
+            None => (rest, None),
+        };
+        let Some(user_id) = collection.strip_prefix('u').and_then(|id| id.parse::<i64>().ok()) else {
+            return Ok(None);
+        };
+        if !self.user_ids()?.contains(&user_id) {
+            return Ok(None);
+        }
+        match item.map(str::parse::<i64>) {
+            None => Ok(Some(Object::Collection(user_id))),
+            Some(Ok(entry_id)) => Ok(Some(Object::Item(user_id, entry_id))),
+            Some(Err(_)) => Ok(None),
+        }
+    }
+
+    fn item(&self, user_id: i64, entry_id: i64) -> std::result::Result<(Session, SecretItem), Failure> {
+        let vault = self.vaults.session(user_id).ok_or_else(Failure::is_locked)?;
+        let item = database::secret_items(&self.conn, &vault)?
+            .into_iter()
+            .find(|item| item.id == entry_id)
+            .ok_or_else(|| Failure::no_such_object(item_path(user_id, entry_id).as_str()))?;
+        Ok((vault, item))
+    }
+
+    fn search(&self, vault: &Session, attributes: &HashMap<String, String>) -> std::result::Result<Vec<OwnedObjectPath>, Failure> {
+        Ok(database::secret_items(&self.conn, vault)?
+            .into_iter()
+            .filter(|item| {
+                let own = item.lookup_attributes();
+                attributes.iter().all(|(name, value)| own.get(name) == Some(value))
+            })
+            .map(|item| item_path(vault.user_id, item.id))
+            .collect())
+    }
+
+    // Splits objects into those of unlocked collections and the rest
+    fn split_unlocked(&self, objects: Vec<OwnedObjectPath>) -> std::result::Result<(Vec<OwnedObjectPath>, Vec<OwnedObjectPath>), Failure> {
+        let mut unlocked = Vec::new();
+        let mut locked = Vec::new();
+        for object in objects {
+            match self.resolve(&object)?.and_then(|resolved| resolved.user_id()) {
+                Some(user_id) if self.vaults.session(user_id).is_some() => unlocked.push(object),
+                _ => locked.push(object),
+            }
+        }
+        Ok((unlocked, locked))
+    }
+
+    // The first unlocked vault, or the first user while all are locked
+    fn default_collection(&self) -> std::result::Result<Option<i64>, Failure> {
+        let user_ids = self.user_ids()?;
+        Ok(user_ids
+            .iter()
+            .copied()
+            .find(|&user_id| self.vaults.session(user_id).is_some())
+            .or(user_ids.first().copied()))
+    }
+
+    fn user_ids(&self) -> Result<Vec<i64>> {
+        Ok(self
+            .conn
+            .prepare("SELECT id FROM users ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn check_session(&self, session: &ObjectPath) -> Reply {
+        if self.sessions.contains(session.as_str()) {
+            Ok(())
+        } else {
+            Err(Failure::no_session())
+        }
+    }
+
+    fn secret_value(&self, secret: Secret) -> std::result::Result<SecretKey, Failure> {
+        let (session, _parameters, value, _content_type) = secret;
+        self.check_session(&session)?;
+        Ok(SecretKey::new(value))
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+}
+
+impl Object {
+    fn user_id(&self) -> Option<i64> {
+        match *self {
+            Object::Collection(user_id) | Object::Item(user_id, _) => Some(user_id),
+            _ => None,
+        }
+    }
+}
+
+fn arguments<'m, T>(message: &'m Message) -> std::result::Result<T, Failure>
+where
+    T: serde::de::Deserialize<'m> + zbus::zvariant::Type,
+{
+    message.body().map_err(|err| Failure::invalid_args(err.to_string()))
+}
+
+fn secret(session: &ObjectPath, password: &str) -> Secret {
+    (session.clone().into(), Vec::new(), password.as_bytes().to_vec(), CONTENT_TYPE.to_string())
+}
+
+// Username of an item stored by another application, from the attribute it most likely keeps it in
+fn item_username(attributes: &HashMap<String, String>) -> String {
+    ["username", "user", "account"]
+        .iter()
+        .find_map(|name| attributes.get(*name))
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn collection_path(user_id: i64) -> OwnedObjectPath {
+    object_path(format!("{}u{}", COLLECTION_PREFIX, user_id))
+}
+
+fn item_path(user_id: i64, entry_id: i64) -> OwnedObjectPath {
+    object_path(format!("{}u{}/{}", COLLECTION_PREFIX, user_id, entry_id))
+}
+
+fn object_path(path: String) -> OwnedObjectPath {
+    OwnedObjectPath::try_from(path).expect("paths are built from valid elements")
+}
@@ -0,0 +1,260 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use crate::secret::SecretKey;
+use crate::{Error, Result};
+
+/// Seconds each code is valid for.
+pub const PERIOD: u64 = 30;
+pub const DIGITS: u32 = 6;
+/// Codes from this many periods before or after the current one are still accepted.
+pub const SKEW: u64 = 1;
+
+const ISSUER: &str = "PasswordManager";
+
+/// 160 random bits, the secret length RFC 4226 recommends.
+pub fn generate_secret() -> SecretKey {
+    let mut secret = vec![0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    SecretKey::new(secret)
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(base32::Alphabet::Rfc4648 { padding: false }, secret)
+}
+
+pub fn decode_secret(encoded: &str) -> Result<SecretKey> {
+    let normalized: String = encoded.chars().filter(|c| !c.is_whitespace() && *c != '=').collect();
+
+    base32::decode(base32::Alphabet::Rfc4648 { padding: false }, &normalized.to_uppercase())
+        .map(SecretKey::new)
+        .ok_or_else(|| Error::Encoding("TOTP secret is not base32".to_string()))
+}
+
+/// `otpauth://` URI authenticator apps import, usually from a QR code.
+pub fn provisioning_uri(secret: &[u8], account: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        ISSUER,
+        percent_encode(account),
+        encode_secret(secret),
+        ISSUER,
+        DIGITS,
+        PERIOD
+    )
+}
+
+/// Hash function of an HOTP or TOTP generator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OtpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl OtpAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OtpAlgorithm::Sha1 => "SHA1",
+            OtpAlgorithm::Sha256 => "SHA256",
+            OtpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+}
+
+/// Whether codes follow the clock or a counter advanced on every use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OtpKind {
+    Totp { period: u64 },
+    Hotp { counter: u64 },
+}
+
+/// Code generator described by an `otpauth://` URI, as stored with a vault entry.
+#[derive(Clone, Debug)]
+pub struct OtpAuth {
+    pub kind: OtpKind,
+    pub algorithm: OtpAlgorithm,
+    pub digits: u32,
+    pub label: String,
+    pub issuer: Option<String>,
+    secret: SecretKey,
+}
+
+impl OtpAuth {
+    /// Parses an `otpauth://totp/...` or `otpauth://hotp/...` URI, applying the usual defaults
+    /// of SHA1, 6 digits and a 30 second period. Fails with `Error::Encoding`.
+    pub fn parse(uri: &str) -> Result<OtpAuth> {
+        let invalid = |reason: &str| Error::Encoding(format!("otpauth URI: {}", reason));
+
+        let rest = uri
+            .trim()
+            .get(.."otpauth://".len())
+            .filter(|scheme| scheme.eq_ignore_ascii_case("otpauth://"))
+            .map(|scheme| &uri.trim()[scheme.len()..])
+            .ok_or_else(|| invalid("does not start with otpauth://"))?;
+        let (kind, rest) = rest.split_once('/').ok_or_else(|| invalid("missing type"))?;
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let (mut secret, mut algorithm, mut digits, mut period, mut counter, mut issuer) =
+            (None, OtpAlgorithm::Sha1, DIGITS, PERIOD, None, None);
+        for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            let value = percent_decode(value).ok_or_else(|| invalid("bad percent-encoding"))?;
+            match name.to_ascii_lowercase().as_str() {
+                "secret" => secret = Some(decode_secret(&value)?),
+                "algorithm" => {
+                    algorithm = match value.to_ascii_uppercase().as_str() {
+                        "SHA1" => OtpAlgorithm::Sha1,
+                        "SHA256" => OtpAlgorithm::Sha256,
+                        "SHA512" => OtpAlgorithm::Sha512,
+                        _ => return Err(invalid("unsupported algorithm")),
+                    }
+                }
+                "digits" => digits = value.parse().ok().filter(|digits| (6..=8).contains(digits)).ok_or_else(|| invalid("digits must be 6 to 8"))?,
+                "period" => period = value.parse().ok().filter(|&period| period > 0).ok_or_else(|| invalid("bad period"))?,
+                "counter" => counter = Some(value.parse().map_err(|_| invalid("bad counter"))?),
+                "issuer" => issuer = Some(value),
+                _ => {}
+            }
+        }
+
+        let kind = match kind.to_ascii_lowercase().as_str() {
+            "totp" => OtpKind::Totp { period },
+            "hotp" => OtpKind::Hotp { counter: counter.ok_or_else(|| invalid("hotp needs a counter"))? },
+            _ => return Err(invalid("type must be totp or hotp")),
+        };
+        let secret = secret.filter(|secret| !secret.is_empty()).ok_or_else(|| invalid("missing secret"))?;
+        let label = percent_decode(label).ok_or_else(|| invalid("bad percent-encoding"))?;
+
+        Ok(OtpAuth { kind, algorithm, digits, label, issuer, secret })
+    }
+
+    /// URI describing this generator, in the form `parse` reads.
+    pub fn to_uri(&self) -> String {
+        let (kind, moving_factor) = match self.kind {
+            OtpKind::Totp { period } => ("totp", format!("period={}", period)),
+            OtpKind::Hotp { counter } => ("hotp", format!("counter={}", counter)),
+        };
+        let issuer = self
+            .issuer
+            .as_ref()
+            .map(|issuer| format!("&issuer={}", percent_encode(issuer)))
+            .unwrap_or_default();
+
+        format!(
+            "otpauth://{}/{}?secret={}{}&algorithm={}&digits={}&{}",
+            kind,
+            percent_encode(&self.label).replace("%3A", ":"),
+            encode_secret(&self.secret),
+            issuer,
+            self.algorithm.as_str(),
+            self.digits,
+            moving_factor
+        )
+    }
+
+    /// Current code: the one for `unix_time` of a TOTP generator, the one for the stored counter of an HOTP generator.
+    pub fn code(&self, unix_time: u64) -> String {
+        let counter = match self.kind {
+            OtpKind::Totp { period } => unix_time / period,
+            OtpKind::Hotp { counter } => counter,
+        };
+
+        hotp_with(self.algorithm, &self.secret, counter, self.digits)
+    }
+
+    /// Seconds until the TOTP code changes, `None` for HOTP.
+    pub fn seconds_left(&self, unix_time: u64) -> Option<u64> {
+        match self.kind {
+            OtpKind::Totp { period } => Some(period - unix_time % period),
+            OtpKind::Hotp { .. } => None,
+        }
+    }
+
+    /// The generator after an HOTP code has been used; TOTP generators do not change.
+    pub fn next_counter(&self) -> OtpAuth {
+        let mut next = self.clone();
+        if let OtpKind::Hotp { counter } = &mut next.kind {
+            *counter += 1;
+        }
+        next
+    }
+}
+
+/// RFC 4226 code for `counter`.
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> String {
+    hotp_with(OtpAlgorithm::Sha1, secret, counter, digits)
+}
+
+/// `hotp` with the hash function chosen, as RFC 6238 allows.
+pub fn hotp_with(algorithm: OtpAlgorithm, secret: &[u8], counter: u64, digits: u32) -> String {
+    let hash = match algorithm {
+        OtpAlgorithm::Sha1 => hmac::<Hmac<Sha1>>(secret, counter),
+        OtpAlgorithm::Sha256 => hmac::<Hmac<Sha256>>(secret, counter),
+        OtpAlgorithm::Sha512 => hmac::<Hmac<Sha512>>(secret, counter),
+    };
+
+    // Dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+
+    format!("{:0width$}", binary % 10u32.pow(digits), width = digits as usize)
+}
+
+/// RFC 6238 code valid at `unix_time`.
+pub fn totp(secret: &[u8], unix_time: u64) -> String {
+    hotp(secret, unix_time / PERIOD, DIGITS)
+}
+
+/// Time step `code` belongs to, looking up to `SKEW` periods around `unix_time`.
+pub fn matching_step(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    let current = unix_time / PERIOD;
+
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|&step| constant_time_eq(hotp(secret, step, DIGITS).as_bytes(), code.trim().as_bytes()))
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn hmac<M: Mac + KeyInit>(secret: &[u8], counter: u64) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
@@ -0,0 +1,68 @@
+-- Schema of the last release before PRAGMA user_version was kept
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT UNIQUE NOT NULL,
+    password_hash TEXT NOT NULL,
+    key_salt TEXT,
+    key_version INTEGER,
+    rotated_at INTEGER,
+    rotation_interval_days INTEGER NOT NULL DEFAULT 90,
+    kdf_algorithm TEXT NOT NULL DEFAULT 'argon2id',
+    kdf_m_cost INTEGER NOT NULL DEFAULT 19456,
+    kdf_t_cost INTEGER NOT NULL DEFAULT 2,
+    kdf_p_cost INTEGER NOT NULL DEFAULT 1,
+    entries_bound INTEGER NOT NULL DEFAULT 0,
+    cipher TEXT NOT NULL DEFAULT 'aes-256-gcm',
+    metadata_encrypted INTEGER NOT NULL DEFAULT 0,
+    totp_secret BLOB,
+    totp_last_step INTEGER,
+    credential_reset INTEGER NOT NULL DEFAULT 0,
+    recovery_key_wrapped BLOB,
+    key_file_required INTEGER NOT NULL DEFAULT 0,
+    failed_logins INTEGER NOT NULL DEFAULT 0,
+    last_failed_login INTEGER
+);
+
+CREATE TABLE passwords (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    account TEXT NOT NULL,
+    username TEXT NOT NULL,
+    password_encrypted BLOB NOT NULL,
+    key_version INTEGER NOT NULL DEFAULT 1,
+    account_encrypted BLOB,
+    username_encrypted BLOB,
+    account_index BLOB,
+    username_index BLOB,
+    otp_encrypted BLOB,
+    attributes_encrypted BLOB,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX passwords_account_index ON passwords (user_id, account_index);
+
+CREATE TABLE user_keys (
+    user_id INTEGER NOT NULL,
+    version INTEGER NOT NULL,
+    wrapped_key BLOB NOT NULL,
+    created_at INTEGER NOT NULL,
+    recovery_wrapped_key BLOB,
+    PRIMARY KEY (user_id, version),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE vault_manifests (
+    user_id INTEGER PRIMARY KEY,
+    counter INTEGER NOT NULL,
+    entries BLOB NOT NULL,
+    mac BLOB NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE recovery_codes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);